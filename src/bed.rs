@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use crate::utils::{IntervalNode, IntervalTree, RecordScores};
+
+/// Colours regions are drawn with, going round again for models with more regions
+const PALETTE: [&str; 12] = [
+    "31,119,180", "255,127,14", "44,160,44", "214,39,40", "148,103,189", "140,86,75",
+    "227,119,194", "127,127,127", "188,189,34", "23,190,207", "174,199,232", "255,187,120",
+];
+
+/// Colour of each region of a model, so a region has the same colour in every record
+#[derive(Debug, Default)]
+pub struct RegionColours<'a> {
+    colours: HashMap<&'a str, &'static str>,
+}
+
+impl<'a> RegionColours<'a> {
+    /// * `region_names`: e.g. from `CompiledHmm::region_names`, which get the palette's colours in order
+    pub fn new(region_names: &[&'a str]) -> Self {
+        let colours = region_names.iter()
+            .enumerate()
+            .map(|(i, name)| (*name, PALETTE[i % PALETTE.len()]))
+            .collect();
+        RegionColours { colours }
+    }
+
+    /// Black for regions it doesn't know
+    pub fn colour(&self, region: &str) -> &'static str {
+        self.colours.get(region).copied().unwrap_or("0,0,0")
+    }
+}
+
+/// BED score of an interval, its confidence scaled to 0-1000 when it has one
+fn bed_score(node: &IntervalNode) -> u32 {
+    node.interval.confidence.map_or(0, |c| (c * 1000.0).round() as u32)
+}
+
+fn bed_strand(scores: &RecordScores) -> char {
+    scores.strand.unwrap_or('.')
+}
+
+/// Track line that turns on the itemRgb colours in genome browsers
+/// * `writer`:
+pub fn write_bed_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "track itemRgb=\"On\"")
+}
+
+/// Writes a BED9 line per interval, with the record ID as the chromosome and the region name as the
+/// name, coloured by region
+/// * `writer`:
+/// * `seqname`:
+/// * `intervals`:
+/// * `scores`:
+/// * `colours`:
+pub fn write_bed<W: Write>(writer: &mut W, seqname: &str, intervals: &IntervalTree, scores: &RecordScores, colours: &RegionColours) -> io::Result<()> {
+    let strand = bed_strand(scores);
+    for node in intervals.nodes.iter() {
+        let (start, stop) = (node.interval.start, node.interval.stop);
+        writeln!(
+            writer, "{seqname}\t{start}\t{stop}\t{}\t{}\t{strand}\t{start}\t{stop}\t{}",
+            node.name, bed_score(node), colours.colour(node.name)
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes a single BED12 line for the record, with the record ID as the chromosome and the name.
+/// The blocks are the innermost non-empty intervals (e.g. each hex and VNTR copy rather than
+/// their `hexamer_region` and `VNTR_region`), and the line spans the first to the last of them.
+/// Nothing is written for a record without any
+/// * `writer`:
+/// * `seqname`:
+/// * `intervals`:
+/// * `scores`:
+pub fn write_bed12<W: Write>(writer: &mut W, seqname: &str, intervals: &IntervalTree, scores: &RecordScores) -> io::Result<()> {
+    let mut blocks = intervals.nodes.iter()
+        .filter(|n| n.children.is_empty() && n.interval.stop > n.interval.start)
+        .map(|n| (n.interval.start, n.interval.stop))
+        .collect::<Vec<_>>();
+    // The reverse strand's intervals come in reverse order
+    blocks.sort_unstable();
+    let (Some((start, _)), Some((_, stop))) = (blocks.first().copied(), blocks.last().copied()) else {
+        return Ok(());
+    };
+    let sizes = blocks.iter().map(|(b_start, b_stop)| (b_stop - b_start).to_string()).collect::<Vec<_>>();
+    let starts = blocks.iter().map(|(b_start, _)| (b_start - start).to_string()).collect::<Vec<_>>();
+    writeln!(
+        writer, "{seqname}\t{start}\t{stop}\t{seqname}\t0\t{}\t{start}\t{stop}\t0\t{}\t{},\t{},",
+        bed_strand(scores), blocks.len(), sizes.join(","), starts.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{HMMBuildSettings, create_HMM_from_motifs};
+    use crate::utils::{Interval, sequence_to_bytes};
+
+    #[test]
+    fn bed_export() {
+        let interval = |start, stop| Interval { start, stop, confidence: None, bits: None, alignment: None };
+        let intervals = vec![
+            ("skip", interval(0, 0)),
+            ("region", interval(0, 12)),
+            ("hex", interval(0, 6)),
+            ("hex", interval(7, 12)),
+            ("SINE", interval(12, 20)),
+        ];
+        let tree = IntervalTree::new(intervals, &[None, None, Some(1), Some(1), None]);
+        let scores = RecordScores { strand: Some('-'), ..Default::default() };
+        let colours = RegionColours::new(&["skip", "region", "hex"]);
+
+        let mut bed = Vec::new();
+        write_bed(&mut bed, "read1", &tree, &scores, &colours).unwrap();
+        let bed = String::from_utf8(bed).unwrap();
+        let lines = bed.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[2], format!("read1\t0\t6\thex\t0\t-\t0\t6\t{}", PALETTE[2]));
+        assert!(lines[3].ends_with(PALETTE[2]));
+        assert!(lines[4].ends_with("\t0,0,0"));
+
+        let mut bed12 = Vec::new();
+        write_bed12(&mut bed12, "read1", &tree, &scores).unwrap();
+        let bed12 = String::from_utf8(bed12).unwrap();
+        assert_eq!(bed12, "read1\t0\t20\tread1\t0\t-\t0\t20\t0\t3\t6,5,8,\t0,7,12,\n");
+
+        let mut empty = Vec::new();
+        write_bed12(&mut empty, "read1", &IntervalTree::default(), &scores).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn bed_decoded_spans() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let hmm = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2"], &settings, "test").compile();
+        let query = sequence_to_bytes(&[motifs[0], motifs[1], motifs[0]].concat());
+        let (path, query_indexes) = hmm.query(&query);
+        let tree = hmm.interval_tree(path, query_indexes);
+        let scores = RecordScores::default();
+        let colours = RegionColours::new(&hmm.region_names());
+
+        let mut bed = Vec::new();
+        write_bed(&mut bed, "read1", &tree, &scores, &colours).unwrap();
+        let spans = String::from_utf8(bed).unwrap()
+            .lines()
+            .map(|l| l.split('\t').skip(1).take(3).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, ["0 26 test", "0 9 Rep1", "9 17 Rep2", "17 26 Rep1"]);
+
+        let mut bed12 = Vec::new();
+        write_bed12(&mut bed12, "read1", &tree, &scores).unwrap();
+        assert_eq!(String::from_utf8(bed12).unwrap(), "read1\t0\t26\tread1\t0\t.\t0\t26\t0\t3\t9,8,9,\t0,9,17,\n");
+    }
+}
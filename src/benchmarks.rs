@@ -7,6 +7,7 @@ mod tests {
     use crate::sva;
     use crate::utils::*;
     use crate::builder::*;
+    use crate::compiled::Beam;
 
     // TODO: Can I make a separate function that takes in a closure?
     
@@ -49,4 +50,62 @@ mod tests {
     }
     
 
+    /// SVAs sampled from the model with every family, standing in for real reference SVAs
+    fn sampled_sva_seqs(settings: &HMMBuildSettings) -> Vec<Vec<u8>> {
+        let model = sva::gen_sva_model_with_innerseq_all_families(settings);
+        (0..20).map(|seed| model.sample(seed).0).collect()
+    }
+
+    /// Speed and accuracy of beam viterbi relative to exact viterbi.
+    /// Run with `cargo test --release bench_beam_viterbi -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_beam_viterbi() {
+        let settings = HMMBuildSettings::default();
+        let model = sva::gen_sva_model_with_innerseq_all_families(&settings).compile();
+        let queries = sampled_sva_seqs(&settings);
+
+        let t = time::Instant::now();
+        let exact = queries.iter().map(|q| model.query(q)).collect::<Vec<_>>();
+        let exact_duration = t.elapsed();
+        eprintln!("Exact: {:?}", exact_duration);
+
+        for (width, delta) in [(None, Some(20.0)), (Some(2000), None), (Some(500), None), (Some(500), Some(20.0))] {
+            let beam_model = model.clone().with_beam(Beam { width, delta });
+            let t = time::Instant::now();
+            let beam = queries.iter().map(|q| beam_model.query(q)).collect::<Vec<_>>();
+            let d = t.elapsed();
+            let mismatches = std::iter::zip(&exact, &beam).filter(|(e, b)| e != b).count();
+            eprintln!(
+                "Beam width {:?} delta {:?}: {:?} ({:.2}x), {}/{} paths differ",
+                width, delta, d, exact_duration.as_secs_f64() / d.as_secs_f64(), mismatches, queries.len()
+            );
+        }
+    }
+
+    /// Speed of exact viterbi with and without the vectorised pHMM states, which have to give the
+    /// same paths. Run with `cargo test --release bench_vectorised_viterbi -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_vectorised_viterbi() {
+        let settings = HMMBuildSettings::default();
+        let model = sva::gen_sva_model_with_innerseq_all_families(&settings).compile();
+        let seq_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/SVA_ref_core.fa");
+        let queries = fasta::Reader::from_file(&seq_file).unwrap()
+            .records()
+            .map(|record| sequence_to_bytes(&std::str::from_utf8(record.unwrap().seq()).unwrap().to_uppercase()))
+            .collect::<Vec<_>>();
+
+        let scalar_model = model.clone().with_vectorised(false);
+        let t = time::Instant::now();
+        let scalar = queries.iter().map(|q| scalar_model.query(q)).collect::<Vec<_>>();
+        let scalar_duration = t.elapsed();
+        eprintln!("Scalar: {:?}", scalar_duration);
+
+        let t = time::Instant::now();
+        let vectorised = queries.iter().map(|q| model.query(q)).collect::<Vec<_>>();
+        let d = t.elapsed();
+        eprintln!("Vectorised: {:?} ({:.2}x)", d, scalar_duration.as_secs_f64() / d.as_secs_f64());
+        assert_eq!(scalar, vectorised);
+    }
 }
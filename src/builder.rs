@@ -1,15 +1,79 @@
 use crate::utils::*;
 use crate::hmm::*;
 use thiserror::Error;
+use serde::Serialize;
+use std::io::{self, BufRead, BufReader, Write};
 use std::iter::zip;
+use std::path::Path;
 
 #[derive(Error, Debug)]
 pub enum HMMBuildError {
     #[error("{0} must be between 0 and 1")]
     BuildParameterError(String),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Could not parse settings line \"{0}\"")]
+    SettingsParseError(String),
+    #[error("Invalid copy counts: {0}")]
+    CopyRangeError(String),
 }
 
-#[derive(Clone, Copy)]
+/// How a profile HMM can be entered and left
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AlignmentMode {
+    /// Alignments cover the whole consensus
+    #[default]
+    Global,
+    /// Alignments cover any slice of the consensus, uniformly over all slices like HMMER's local
+    /// configuration (entry into any match state and exit from any match state)
+    Local,
+}
+
+impl AlignmentMode {
+    /// Probability of entering a model of length `len` at match state `i`, so that combined with
+    /// `exit_prob` every slice of the consensus is equally likely
+    pub fn entry_prob(len: usize, i: usize) -> f64 {
+        2.0 * (len - i) as f64 / (len * (len + 1)) as f64
+    }
+
+    /// Probability of leaving a model of length `len` right after match state `i`, given that the
+    /// alignment got there. The last match state is left through the normal transitions
+    pub fn exit_prob(&self, len: usize, i: usize) -> f64 {
+        match self {
+            AlignmentMode::Local if i + 1 < len => 1.0 / (len - i) as f64,
+            _ => 0.0,
+        }
+    }
+}
+
+/// How many times the motifs of a loop (`create_HMM_from_motifs_with_copies`) can be repeated.
+/// The default, at least one copy and no maximum, is a plain loop
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct CopyRange {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl CopyRange {
+    pub fn new(min: usize, max: Option<usize>) -> Result<Self, HMMBuildError> {
+        if min == 0 {
+            return Err(HMMBuildError::CopyRangeError("the minimum must be at least 1".to_string()));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(HMMBuildError::CopyRangeError("the maximum is below the minimum".to_string()));
+        }
+        Ok(CopyRange { min, max })
+    }
+}
+
+impl Default for CopyRange {
+    fn default() -> Self {
+        CopyRange { min: 1, max: None }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub struct HMMBuildSettings {
     pub match_to_match: f64,
     pub match_to_ins: f64,
@@ -19,6 +83,12 @@ pub struct HMMBuildSettings {
     pub enter_skip_loop: f64,
     pub skip_to_skip: f64,
     pub match_emit_correct: f64,
+    /// Used by `create_pHMM` (but not for motif repeat units) and for models read from .hmm files
+    pub alignment_mode: AlignmentMode,
+    /// Copies of the hexamer repeat in the SVA models
+    pub hexamer_copies: CopyRange,
+    /// Copies of the VNTR repeat units in the SVA models
+    pub vntr_copies: CopyRange,
 }
 
 impl HMMBuildSettings {
@@ -67,9 +137,79 @@ impl HMMBuildSettings {
             enter_skip_loop,
             skip_to_skip,
             match_emit_correct,
+            alignment_mode: AlignmentMode::Global,
+            hexamer_copies: CopyRange::default(),
+            vntr_copies: CopyRange::default(),
         })
     }
 
+    pub fn with_alignment_mode(mut self, alignment_mode: AlignmentMode) -> Self {
+        self.alignment_mode = alignment_mode;
+        self
+    }
+
+    pub fn with_hexamer_copies(mut self, hexamer_copies: CopyRange) -> Self {
+        self.hexamer_copies = hexamer_copies;
+        self
+    }
+
+    pub fn with_vntr_copies(mut self, vntr_copies: CopyRange) -> Self {
+        self.vntr_copies = vntr_copies;
+        self
+    }
+
+    /// Writes the settings as tab-separated name/value lines, readable with `read_settings`
+    pub fn write_settings<W: Write>(&self, writer: &mut W) -> Result<(), HMMBuildError> {
+        for (name, value) in self.named_values() {
+            writeln!(writer, "{name}\t{value}")?;
+        }
+        Ok(())
+    }
+
+    /// Reads settings written by `write_settings`. Parameters missing from the file keep their
+    /// default values
+    /// * `path`:
+    pub fn read_settings(path: &Path) -> Result<Self, HMMBuildError> {
+        let reader = BufReader::new(std::fs::File::open(path)?);
+        let mut settings = HMMBuildSettings::default();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parse_error = || HMMBuildError::SettingsParseError(line.clone());
+            let (name, value) = line.split_once('\t').ok_or_else(parse_error)?;
+            let value: f64 = value.trim().parse().map_err(|_| parse_error())?;
+            match name.trim() {
+                "match_to_match" => settings.match_to_match = value,
+                "match_to_ins" => settings.match_to_ins = value,
+                "ins_extend" => settings.ins_extend = value,
+                "del_extend" => settings.del_extend = value,
+                "loop_prob" => settings.loop_prob = value,
+                "enter_skip_loop" => settings.enter_skip_loop = value,
+                "skip_to_skip" => settings.skip_to_skip = value,
+                "match_emit_correct" => settings.match_emit_correct = value,
+                _ => return Err(parse_error()),
+            }
+        }
+        // Go through the constructor so the values are checked
+        let [m2m, m2i, ie, de, lp, esl, s2s, mec] = settings.named_values().map(|(_, v)| v);
+        HMMBuildSettings::new(m2m, m2i, ie, de, lp, esl, s2s, mec)
+    }
+
+    pub(crate) fn named_values(&self) -> [(&'static str, f64); 8] {
+        [
+            ("match_to_match", self.match_to_match),
+            ("match_to_ins", self.match_to_ins),
+            ("ins_extend", self.ins_extend),
+            ("del_extend", self.del_extend),
+            ("loop_prob", self.loop_prob),
+            ("enter_skip_loop", self.enter_skip_loop),
+            ("skip_to_skip", self.skip_to_skip),
+            ("match_emit_correct", self.match_emit_correct),
+        ]
+    }
+
     pub fn match_emission_vec(&self, match_index: u8) -> Vec<f64> {
         let match_emit_incorrect = (1.0 - self.match_emit_correct) / 3_f64;
         let mut emission = vec![match_emit_incorrect; 4];
@@ -95,7 +235,21 @@ impl Default for HMMBuildSettings {
 
 #[allow(non_snake_case)]
 pub fn create_HMM_from_motifs(motifs: &[&str], motifnames: &[&str], settings: &HMMBuildSettings, loop_name: &str) -> HMM {
-    
+    create_HMM_from_motifs_with_copies(motifs, motifnames, settings, loop_name, CopyRange::default())
+}
+
+/// Same as `create_HMM_from_motifs`, with bounds on how many motifs in a row the loop has
+/// * `copies`: see `loop_HMM_with_copies`
+#[allow(non_snake_case)]
+pub fn create_HMM_from_motifs_with_copies(
+    motifs: &[&str],
+    motifnames: &[&str],
+    settings: &HMMBuildSettings,
+    loop_name: &str,
+    copies: CopyRange,
+) -> HMM {
+    // Partial repeat units would make every copy count ambiguous, so they always stay global
+    let settings = &settings.with_alignment_mode(AlignmentMode::Global);
     let motif_hmms = zip(motifs, motifnames)
         .map(|(s, m)| create_pHMM(
                 &sequence_to_bytes(s), settings, Some(m)
@@ -104,7 +258,7 @@ pub fn create_HMM_from_motifs(motifs: &[&str], motifnames: &[&str], settings: &H
     // Now add skip state
     // motif_hmms.push(create_skip_state(settings, Some(loop_name)));
     let mut hmm = parallelize_HMM(motif_hmms, &format!("{loop_name}_loop"));
-    loop_HMM(&mut hmm, loop_name, settings, true);
+    loop_HMM_with_copies(&mut hmm, loop_name, settings, true, copies);
     hmm
 }
 
@@ -122,12 +276,18 @@ pub fn create_pHMM(seq: &[u8], settings: &HMMBuildSettings, prefix: Option<&str>
         }
         None => "".to_string(),
     };
+    let annotation = |role: StateRole, position: Option<usize>| {
+        StateAnnotation::phmm(prefix.trim_end_matches('_'), role, position)
+    };
 
     let match_seed_prob =
         2.0 * (1.0 - settings.match_to_match) / (seq.len() * (seq.len() - 1)) as f64;
+    let local = settings.alignment_mode == AlignmentMode::Local;
+    // Share of a match state's transitions that don't go straight to the end
+    let stay = |i: usize| 1.0 - settings.alignment_mode.exit_prob(seq.len(), i);
 
     // Add start state
-    hmm.add_state(HMMState::empty_state(format!("{prefix}start")));
+    hmm.add_state(HMMState::empty_state(format!("{prefix}start")).with_annotation(annotation(StateRole::Start, None)));
 
     // Add first match and insertion states
     let seq_chars = seq;
@@ -135,19 +295,22 @@ pub fn create_pHMM(seq: &[u8], settings: &HMMBuildSettings, prefix: Option<&str>
         format!("{prefix}M0"),
         Some(settings.match_emission_vec(seq_chars[0])),
         vec![format!("{prefix}start")],
-        vec![settings.match_to_match],
-    ));
+        vec![if local { AlignmentMode::entry_prob(seq.len(), 0) } else { settings.match_to_match }],
+    ).with_annotation(annotation(StateRole::Match, Some(0))));
     hmm.add_state(HMMState::new(
         format!("{prefix}I0"),
         Some(vec![0.25, 0.25, 0.25, 0.25]),
         vec![format!("{prefix}M0"), format!("{prefix}I0")],
-        vec![settings.match_to_ins, settings.ins_extend],
-    ));
+        vec![settings.match_to_ins * stay(0), settings.ins_extend],
+    ).with_annotation(annotation(StateRole::Insert, Some(0))));
 
     for (i, c) in seq_chars.iter().enumerate().skip(1) {
         // Add match
         //
-        let mismatch_prob = match_seed_prob * ((seq_chars.len() - i) as f64);
+        let mismatch_prob = match local {
+            true => AlignmentMode::entry_prob(seq.len(), i),
+            false => match_seed_prob * ((seq_chars.len() - i) as f64),
+        };
         let mut match_prev_states = vec![
             format!("{prefix}start"),
             format!("{prefix}M{}", i - 1),
@@ -155,7 +318,7 @@ pub fn create_pHMM(seq: &[u8], settings: &HMMBuildSettings, prefix: Option<&str>
         ];
         let mut match_prev_state_probs = vec![
             mismatch_prob,
-            settings.match_to_match,
+            settings.match_to_match * stay(i - 1),
             (1.0 - settings.ins_extend),
         ];
         if i > 1 {
@@ -169,19 +332,19 @@ pub fn create_pHMM(seq: &[u8], settings: &HMMBuildSettings, prefix: Option<&str>
             Some(settings.match_emission_vec(*c)),
             match_prev_states,
             match_prev_state_probs,
-        ));
+        ).with_annotation(annotation(StateRole::Match, Some(i))));
 
         // Add Insertion State
         hmm.add_state(HMMState::new(
             format!("{prefix}I{i}"),
             Some(vec![0.25, 0.25, 0.25, 0.25]),
             vec![format!("{prefix}M{i}"), format!("{prefix}I{i}")],
-            vec![settings.match_to_ins, settings.ins_extend],
-        ));
+            vec![settings.match_to_ins * stay(i), settings.ins_extend],
+        ).with_annotation(annotation(StateRole::Insert, Some(i))));
 
         let mut del_prev_states = vec![format!("{prefix}M{}", i - 1)];
         let mut del_prev_state_probs =
-            vec![(1.0 - settings.match_to_ins - settings.match_to_match)];
+            vec![(1.0 - settings.match_to_ins - settings.match_to_match) * stay(i - 1)];
 
         if i > 1 {
             // Only add previous deletion state if i > 1
@@ -195,19 +358,27 @@ pub fn create_pHMM(seq: &[u8], settings: &HMMBuildSettings, prefix: Option<&str>
             None,
             del_prev_states,
             del_prev_state_probs,
-        ));
+        ).with_annotation(annotation(StateRole::Delete, Some(i))));
     }
     // Add final final state
+    let mut end_prev_states = vec![
+        format!("{prefix}M{}", seq_chars.len() - 1),
+        format!("{prefix}I{}", seq_chars.len() - 1),
+        format!("{prefix}D{}", seq_chars.len() - 1),
+    ];
+    let mut end_prev_state_probs = vec![1.0 - settings.match_to_ins, 1.0 - settings.ins_extend, 1.0];
+    if local {
+        for i in 0..seq_chars.len() - 1 {
+            end_prev_states.push(format!("{prefix}M{i}"));
+            end_prev_state_probs.push(1.0 - stay(i));
+        }
+    }
     hmm.add_state(HMMState::new(
         format!("{prefix}end"),
         None,
-        vec![
-            format!("{prefix}M{}", seq_chars.len() - 1),
-            format!("{prefix}I{}", seq_chars.len() - 1),
-            format!("{prefix}D{}", seq_chars.len() - 1),
-        ],
-        vec![1.0 - settings.match_to_ins, 1.0 - settings.ins_extend, 1.0],
-    ));
+        end_prev_states,
+        end_prev_state_probs,
+    ).with_annotation(annotation(StateRole::End, None)));
     hmm.order_states();
     hmm
 }
@@ -225,21 +396,22 @@ pub fn create_skip_state(settings: &HMMBuildSettings, prefix: Option<&str>) -> H
         }
         None => "".to_string(),
     };
-    hmm.add_state(HMMState::empty_state(format!("{prefix}skip_start")));
+    let region = format!("{prefix}skip");
+    hmm.add_state(HMMState::empty_state(format!("{prefix}skip_start")).with_annotation(StateAnnotation::new(&region, StateRole::Start)));
 
     hmm.add_state(HMMState::new(
             format!("{prefix}skip_state"),
             Some(vec![0.25, 0.25, 0.25, 0.25]),
             vec![format!("{prefix}skip_start"), format!("{prefix}skip_state")],
             vec![1.0, settings.skip_to_skip]
-    ));
+    ).with_annotation(StateAnnotation::new(&region, StateRole::Skip)));
 
     hmm.add_state(HMMState::new(
             format!("{prefix}skip_end"),
             None,
             vec![format!("{prefix}skip_state")],
             vec![1.0-settings.skip_to_skip]
-    ));
+    ).with_annotation(StateAnnotation::new(&region, StateRole::End)));
     hmm.order_states();
 
     hmm
@@ -301,7 +473,8 @@ pub fn parallelize_HMM(hmms: Vec<HMM>, region_prefix: &str) -> HMM {
 
     let transition_prob = 1.0 / hmms.len() as f64;
 
-    new_hmm.add_state(HMMState::empty_state(format!("{region_prefix}start")));
+    let region = region_prefix.trim_end_matches('_');
+    new_hmm.add_state(HMMState::empty_state(format!("{region_prefix}start")).with_annotation(StateAnnotation::new(region, StateRole::Start)));
 
     for hmm in hmms.iter() {
         for state in hmm.states.iter() {
@@ -321,16 +494,28 @@ pub fn parallelize_HMM(hmms: Vec<HMM>, region_prefix: &str) -> HMM {
         None,
         all_ends.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
         vec![1.0; all_ends.len()]
-    ));
+    ).with_annotation(StateAnnotation::new(region, StateRole::End)));
     new_hmm.order_states();
     new_hmm
 }
 
 #[allow(non_snake_case)]
 pub fn loop_HMM(hmm: &mut HMM, loop_prefix: &str, settings: &HMMBuildSettings, skip_loop: bool) {
+    loop_HMM_with_copies(hmm, loop_prefix, settings, skip_loop, CopyRange::default())
+}
 
-
-    //TODO: Actually incorporate skip_loop, right now defaults to true
+/// Same as `loop_HMM`, with bounds on how many times `hmm` is gone through. An HMM can't count, so
+/// the loop is unrolled: the minimum number of copies of `hmm` are chained, then optional copies
+/// up to the maximum. Copies after the first get their own state names (`copy_state_name`).
+/// Without a maximum the last required copy loops back on itself like in `loop_HMM`. Going on to
+/// the next copy is not optional before the minimum, otherwise it's `loop_prob` as usual
+/// * `hmm`: a single start and end HMM, e.g. from `parallelize_HMM`
+/// * `loop_prefix`:
+/// * `settings`:
+/// * `skip_loop`: whether there's a skip state between copies, for the junk between repeat units
+/// * `copies`:
+#[allow(non_snake_case)]
+pub fn loop_HMM_with_copies(hmm: &mut HMM, loop_prefix: &str, settings: &HMMBuildSettings, skip_loop: bool, copies: CopyRange) {
     let loop_prefix = if loop_prefix.ends_with('_') {
         loop_prefix.to_string()
     } else {
@@ -339,61 +524,115 @@ pub fn loop_HMM(hmm: &mut HMM, loop_prefix: &str, settings: &HMMBuildSettings, s
     // I had to change it to String instead of &str because the borrowing got complex
     let start_state_id = hmm.get_start_states()[0].to_string();
     let end_state_id = hmm.get_end_states()[0].to_string();
+    let copy_start = |copy: usize| copy_state_name(&start_state_id, copy);
+    let copy_end = |copy: usize| copy_state_name(&end_state_id, copy);
+
+    let n_copies = copies.max.unwrap_or(copies.min);
+    // Leaving a copy: (to the end, to the next copy, to the skip state before the next copy)
+    let leave_probs = |copy: usize| {
+        let loop_prob = settings.loop_prob;
+        let enter_skip = if skip_loop { settings.enter_skip_loop } else { 0.0 };
+        if copy < copies.min {
+            (0.0, 1.0 - enter_skip, enter_skip)
+        } else if copies.max == Some(copy) {
+            (1.0, 0.0, 0.0)
+        } else {
+            (1.0 - loop_prob, loop_prob * (1.0 - enter_skip), loop_prob * enter_skip)
+        }
+    };
+    let has_next = |copy: usize| copy < n_copies || copies.max.is_none();
+
+    // The region around the copies is the loop's, they just go round it
+    let region = loop_prefix.trim_end_matches('_');
+    for state in hmm.states.iter_mut() {
+        if state.identifier == start_state_id {
+            state.set_annotation(StateAnnotation::new(region, StateRole::LoopStart));
+        } else if state.identifier == end_state_id {
+            state.set_annotation(StateAnnotation::new(region, StateRole::LoopEnd));
+        }
+    }
 
-    let mut skip = create_skip_state(settings, Some(loop_prefix.as_str()));
-    let skip_start_id = skip.get_start_states()[0].to_string();
-    let skip_end_id = skip.get_end_states()[0].to_string();
-
+    let unit = hmm.states.clone();
+    for copy in 2..=n_copies {
+        hmm.states.extend(unit.iter().map(|s| rename_state(s, copy)));
+    }
 
     hmm.add_state(HMMState::empty_state(
         format!("{loop_prefix}start")
-    ));
+    ).with_annotation(StateAnnotation::new(region, StateRole::Start)));
 
+    let exits = (copies.min..=n_copies).collect::<Vec<_>>();
     hmm.add_state(HMMState::new(
         format!("{loop_prefix}end"),
         None,
-        vec![end_state_id.clone()],
-        vec![1.0-settings.loop_prob]
-    ));
-    let start_state = hmm.states.iter_mut().find(|s|
-        s.identifier == start_state_id
-    ).expect("Could not find start state");
-
-    start_state.set_transitions(
-        vec![
-            format!("{loop_prefix}start"),
-            end_state_id.clone(),
-            skip_end_id.clone()
-        ],
-        vec![
-            1.0,
-            settings.loop_prob * (1.0- settings.enter_skip_loop),
-            1.0
-        ]
-    );
-
-    // Now add in the skip state
-    let skip_start = skip.states.iter_mut().find(|s|
-        s.identifier == skip_start_id
-    ).expect("Could not find loop start state");
-
-    skip_start.set_transitions(
-        vec![end_state_id.clone()],
-        vec![settings.loop_prob * settings.enter_skip_loop]
-    );
+        exits.iter().map(|c| copy_end(*c)).collect(),
+        exits.iter().map(|c| leave_probs(*c).0).collect()
+    ).with_annotation(StateAnnotation::new(region, StateRole::End)));
+
+    // A skip state after every copy that can be followed by another one
+    let skip = create_skip_state(settings, Some(loop_prefix.as_str()));
+    let skip_end = |copy: usize| copy_state_name(skip.get_end_states()[0], copy);
+    for copy in (1..=n_copies).filter(|c| skip_loop && has_next(*c)) {
+        let skip_start_id = copy_state_name(skip.get_start_states()[0], copy);
+        let mut skip_states = skip.states.iter().map(|s| rename_state(s, copy)).collect::<Vec<_>>();
+
+        // Now add in the skip state
+        let skip_start = skip_states.iter_mut().find(|s|
+            s.identifier == skip_start_id
+        ).expect("Could not find loop start state");
+
+        skip_start.set_transitions(
+            vec![copy_end(copy)],
+            vec![leave_probs(copy).2]
+        );
+        hmm.states.extend(skip_states);
+    }
 
-    for state in skip.states {
-        hmm.states.push(state);
+    for copy in 1..=n_copies {
+        let mut prev_states = Vec::new();
+        let mut prev_state_transitions = Vec::new();
+        if copy == 1 {
+            prev_states.push(format!("{loop_prefix}start"));
+            prev_state_transitions.push(1.0);
+        } else {
+            prev_states.push(copy_end(copy - 1));
+            prev_state_transitions.push(leave_probs(copy - 1).1);
+            if skip_loop {
+                prev_states.push(skip_end(copy - 1));
+                prev_state_transitions.push(1.0);
+            }
+        }
+        if copy == n_copies && copies.max.is_none() {
+            prev_states.push(copy_end(copy));
+            prev_state_transitions.push(leave_probs(copy).1);
+            if skip_loop {
+                prev_states.push(skip_end(copy));
+                prev_state_transitions.push(1.0);
+            }
+        }
+        let start_state = hmm.states.iter_mut().find(|s|
+            s.identifier == copy_start(copy)
+        ).expect("Could not find start state");
+        start_state.set_transitions(prev_states, prev_state_transitions);
     }
 
     hmm.order_states();
 }
 
+/// Copy of a state with the names of it and its previous states changed by `copy_state_name`
+fn rename_state(state: &HMMState, copy: usize) -> HMMState {
+    HMMState::new_with_logprob(
+        copy_state_name(&state.identifier, copy),
+        state.emission().map(|e| e.to_vec()),
+        state.prev_states().iter().map(|s| copy_state_name(s, copy)).collect(),
+        state.prev_state_transitions().to_vec()
+    ).with_annotation(state.annotation().clone())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-
     #[test]
     fn build_test() {
         let seq = sequence_to_bytes("AC");
@@ -411,6 +650,17 @@ mod tests {
         hmm.check_valid();
     }
 
+    #[test]
+    fn local_phmm() {
+        let consensus = "ACGTTGCAAGCTTAGGCATCGAT";
+        let settings = HMMBuildSettings::default().with_alignment_mode(AlignmentMode::Local);
+        let hmm = create_pHMM(&sequence_to_bytes(consensus), &settings, Some("test"));
+        hmm.check_valid();
+
+        let (path, _) = hmm.query(&sequence_to_bytes(&consensus[6..15]));
+        assert_eq!(path, ["test_start", "test_M6", "test_M7", "test_M8", "test_M9", "test_M10", "test_M11", "test_M12", "test_M13", "test_M14", "test_end"]);
+    }
+
     #[test]
     fn skip_state_valid() {
         let settings = HMMBuildSettings::default();
@@ -431,6 +681,32 @@ mod tests {
         hmm.check_valid();
     }
 
+    #[test]
+    fn validation_report() {
+        let settings = HMMBuildSettings::default();
+        let mut hmm = create_pHMM(&sequence_to_bytes("ACGT"), &settings, Some("test"));
+        hmm.add_state(HMMState::new("test_M1".to_string(), Some(vec![0.5, 0.5, 0.5, 0.5]), vec!["test_M0".to_string()], vec![0.1]));
+        hmm.add_state(HMMState::new("loose".to_string(), None, vec!["missing".to_string(), "test_end".to_string()], vec![1.0]));
+
+        let report = hmm.validate().unwrap_err();
+        for error in [
+            HmmValidationError::DuplicateIdentifier("test_M1".to_string(), 2),
+            HmmValidationError::EmissionSum("test_M1".to_string(), 2.0),
+            HmmValidationError::MissingPrevState { state: "loose".to_string(), prev_state: "missing".to_string() },
+            HmmValidationError::TransitionCountMismatch { state: "loose".to_string(), prev_states: 2, transitions: 1 },
+            HmmValidationError::TransitionSum("test_M0".to_string(), 1.1),
+        ] {
+            assert!(report.0.contains(&error), "{error} not in {report}");
+        }
+
+        let mut hmm = HMM::new();
+        hmm.add_state(HMMState::empty_state("start".to_string()));
+        hmm.add_state(HMMState::new("a".to_string(), None, vec!["start".to_string(), "b".to_string()], vec![1.0, 1.0]));
+        hmm.add_state(HMMState::new("b".to_string(), None, vec!["a".to_string()], vec![1.0]));
+        assert!(matches!(hmm.try_order_states(), Err(HmmValidationError::SilentCycle(_))));
+        assert!(hmm.validate().unwrap_err().0.contains(&HmmValidationError::EndStates(vec![])));
+    }
+
     #[test]
     fn query_test() {
         let seq = sequence_to_bytes("ACGT");
@@ -443,6 +719,19 @@ mod tests {
         // panic!();
     }
 
+    #[test]
+    fn settings_file_roundtrip() {
+        let settings = HMMBuildSettings::new(0.95, 0.01, 0.2, 0.3, 0.8, 0.001, 0.75, 0.85).unwrap();
+        let path = std::env::temp_dir().join("sva_typer_settings_roundtrip.tsv");
+        settings.write_settings(&mut std::fs::File::create(&path).unwrap()).unwrap();
+        assert_eq!(HMMBuildSettings::read_settings(&path).unwrap(), settings);
+
+        std::fs::write(&path, "match_to_match\t1.5\n").unwrap();
+        assert!(matches!(HMMBuildSettings::read_settings(&path), Err(HMMBuildError::BuildParameterError(_))));
+        std::fs::write(&path, "match_to_mismatch\t0.5\n").unwrap();
+        assert!(matches!(HMMBuildSettings::read_settings(&path), Err(HMMBuildError::SettingsParseError(_))));
+    }
+
     #[test]
     fn complex_query_test() {
         let settings = HMMBuildSettings::default();
@@ -481,4 +770,96 @@ mod tests {
         // panic!();
 
     }
+
+    #[test]
+    fn copy_range_test() {
+        assert!(CopyRange::new(0, None).is_err());
+        assert!(CopyRange::new(3, Some(2)).is_err());
+
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let count_copies = |hmm: &HMM, path: Vec<&str>, query_indexes: Vec<usize>| {
+            hmm.convert_to_intervals(path, query_indexes).iter().filter(|(n, _)| n.starts_with("Rep")).count()
+        };
+        let copies = |hmm: &HMM, query: &[u8]| {
+            let (path, query_indexes) = hmm.query(query);
+            count_copies(hmm, path, query_indexes)
+        };
+        let one_copy = sequence_to_bytes(motifs[0]);
+        let five_copies = sequence_to_bytes(&[motifs[0], motifs[1], motifs[0], motifs[1], motifs[0]].concat());
+
+        let unbounded = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2"], &settings, "test");
+        assert_eq!(copies(&unbounded, &one_copy), 1);
+        assert_eq!(copies(&unbounded, &five_copies), 5);
+
+        let at_least_two = create_HMM_from_motifs_with_copies(
+            &motifs, &["Rep1", "Rep2"], &settings, "test", CopyRange::new(2, None).unwrap()
+        );
+        assert!(at_least_two.validate().is_ok());
+        assert_eq!(copies(&at_least_two, &one_copy), 2);
+        assert_eq!(copies(&at_least_two, &five_copies), 5);
+
+        let two_to_three = create_HMM_from_motifs_with_copies(
+            &motifs, &["Rep1", "Rep2"], &settings, "test", CopyRange::new(2, Some(3)).unwrap()
+        );
+        assert!(two_to_three.validate().is_ok());
+        assert_eq!(copies(&two_to_three, &five_copies), 3);
+        for seed in 0..20 {
+            let (_, path, query_indexes) = two_to_three.sample(seed);
+            let n = count_copies(&two_to_three, path, query_indexes);
+            assert!((2..=3).contains(&n), "seed {seed}: {n} copies");
+        }
+    }
+
+    #[test]
+    fn loop_without_skip() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let unit = || parallelize_HMM(
+            motifs.iter().zip(["Rep1", "Rep2"]).map(|(m, n)| create_pHMM(&sequence_to_bytes(m), &settings, Some(n))).collect(),
+            "test_loop"
+        );
+        let five_copies = sequence_to_bytes(&[motifs[0], motifs[1], motifs[0], motifs[1], motifs[0]].concat());
+        for copies in [CopyRange::default(), CopyRange::new(2, Some(3)).unwrap()] {
+            let mut hmm = unit();
+            loop_HMM_with_copies(&mut hmm, "test", &settings, false, copies);
+            assert!(hmm.validate().is_ok());
+            assert!(hmm.states.iter().all(|s| s.annotation().role != StateRole::Skip));
+            let (path, query_indexes) = hmm.query(&five_copies);
+            let n = hmm.convert_to_intervals(path, query_indexes).iter().filter(|(n, _)| n.starts_with("Rep")).count();
+            assert_eq!(n, copies.max.unwrap_or(5));
+        }
+    }
+
+    #[test]
+    fn annotated_intervals() {
+        // Names that look like the builder's own state names don't confuse the intervals
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let hmm = create_HMM_from_motifs(&motifs, &["my_end", "x_loop_start"], &settings, "test");
+        let index_map = hmm.get_index_map();
+        let annotation = hmm.states[index_map["my_end_M3"]].annotation();
+        assert_eq!((annotation.role, annotation.position, annotation.motif.as_deref()), (StateRole::Match, Some(3), Some("my_end")));
+        assert_eq!(hmm.states[index_map["test_loop_end"]].annotation().role, StateRole::LoopEnd);
+
+        let query = sequence_to_bytes(&[motifs[0], motifs[1], motifs[0]].concat());
+        let (path, query_indexes) = hmm.query(&query);
+        let intervals = hmm.convert_to_intervals(path, query_indexes)
+            .into_iter()
+            .map(|(n, i)| (n, i.start, i.stop))
+            .collect::<Vec<_>>();
+        assert_eq!(intervals, [("test", 0, 26), ("my_end", 0, 9), ("x_loop_start", 9, 17), ("my_end", 17, 26)]);
+    }
+
+    #[test]
+    fn unnamed_phmm_intervals() {
+        // Without a prefix there's no region to name an interval after
+        let settings = HMMBuildSettings::default();
+        let hmm = create_pHMM(&sequence_to_bytes("ACGTAC"), &settings, None);
+        let (path, query_indexes) = hmm.query(&sequence_to_bytes("ACGTAC"));
+        assert_eq!(path.first(), Some(&"start"));
+        assert!(hmm.convert_to_intervals(path.clone(), query_indexes.clone()).is_empty());
+        assert!(hmm.interval_tree(path, query_indexes).nodes.is_empty());
+        assert!(hmm.compile().region_names().is_empty());
+    }
 }
@@ -0,0 +1,82 @@
+use clap::ValueEnum;
+use crate::compiled::CompiledHmm;
+use crate::null_model::NullModel;
+use crate::utils::QuerySeq;
+
+/// What models are ranked by when classifying a record
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ClassifyScore {
+    /// Forward log-likelihood of the record (natural log)
+    #[default]
+    LogLikelihood,
+    /// Forward log-odds score against the null model in bits
+    Bits,
+}
+
+/// Score of one of the competing models for a record
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelRank<'a> {
+    pub name: &'a str,
+    pub score: f64,
+    /// How much better this model scores than the next best one, so for the best model this is the
+    /// margin over the runner-up. None for the worst model
+    pub margin: Option<f64>,
+}
+
+/// Scores the query against every model on its own and ranks the models, best first. Models that
+/// score the same keep their order
+/// * `models`: names and models
+/// * `query`:
+/// * `score`:
+/// * `null_model`: only used for `ClassifyScore::Bits`
+pub fn rank_models<'a, Q: QuerySeq + ?Sized>(
+    models: &'a [(String, CompiledHmm)],
+    query: &Q,
+    score: ClassifyScore,
+    null_model: &NullModel,
+) -> Vec<ModelRank<'a>> {
+    let mut ranks = models.iter()
+        .map(|(name, hmm)| {
+            let log_likelihood = hmm.forward(query);
+            let score = match score {
+                ClassifyScore::LogLikelihood => log_likelihood,
+                ClassifyScore::Bits => null_model.bits(query.codes(), log_likelihood),
+            };
+            ModelRank { name, score, margin: None }
+        })
+        .collect::<Vec<_>>();
+    ranks.sort_by(|a, b| b.score.total_cmp(&a.score));
+    for i in 1..ranks.len() {
+        ranks[i - 1].margin = Some(ranks[i - 1].score - ranks[i].score);
+    }
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::*;
+    use crate::utils::sequence_to_bytes;
+
+    #[test]
+    fn rank_competing_models() {
+        let settings = HMMBuildSettings::default();
+        let models = [("AC", "ACACACACAC"), ("GT", "GTGTGTGTGT"), ("AT", "ATATATATAT")]
+            .map(|(name, consensus)| {
+                let hmm = create_pHMM(&sequence_to_bytes(consensus), &settings, Some(name));
+                (name.to_string(), hmm.compile())
+            });
+        let query = sequence_to_bytes("ACACACTCAC");
+        let null_model = NullModel::uniform();
+        let ranks = rank_models(&models, &query, ClassifyScore::LogLikelihood, &null_model);
+        assert_eq!(ranks.iter().map(|r| r.name).collect::<Vec<_>>(), ["AC", "AT", "GT"]);
+        assert_eq!(ranks[0].margin, Some(ranks[0].score - ranks[1].score));
+        assert!(ranks[0].margin.unwrap() > 0.0);
+        assert_eq!(ranks[2].margin, None);
+
+        // Bits only shift every score by the same null model log-likelihood
+        let bits = rank_models(&models, &query, ClassifyScore::Bits, &null_model);
+        assert_eq!(bits.iter().map(|r| r.name).collect::<Vec<_>>(), ["AC", "AT", "GT"]);
+        assert!((bits[0].margin.unwrap() - ranks[0].margin.unwrap() / std::f64::consts::LN_2).abs() < 1e-9);
+    }
+}
@@ -1,8 +1,11 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::builder::{HMMBuildSettings, HMMBuildError};
+use crate::builder::{AlignmentMode, CopyRange, HMMBuildSettings, HMMBuildError};
+use crate::utils::OutputColumns;
+use crate::compiled::{Beam, DEFAULT_VITERBI_MEMORY_BUDGET};
+use crate::classify::ClassifyScore;
 use anyhow::Result;
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(ValueEnum, Clone, Debug)]
 pub enum SVAModelType {
@@ -11,6 +14,28 @@ pub enum SVAModelType {
     ComplexAllFamilies
 }
 
+#[derive(ValueEnum, Clone, Debug)]
+pub enum DecodeMethod {
+    /// Single most likely state path
+    Viterbi,
+    /// Path that maximises the product of the posterior probabilities of its states
+    Posterior
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    /// Tab separated intervals, with the optional columns
+    Tsv,
+    /// BED9 line per interval, coloured by region
+    Bed,
+    /// BED12 line per record, with the motif copies as blocks
+    Bed12,
+    /// GFF3 features for the element, its regions and the motif copies, linked through Parent
+    Gff3,
+    /// JSON object per record with the model, its settings, the nested intervals of each path with the scores that were asked for, and any warnings
+    Jsonl
+}
+
 fn between_0_1_parser(s: &str) -> Result<f64> {
     let val: f64 = s.parse()?;
     if (0.0..=1.0).contains(&val) {
@@ -19,17 +44,52 @@ fn between_0_1_parser(s: &str) -> Result<f64> {
         Err(anyhow::anyhow!("Value must be between 0 and 1"))
     }
 }
+#[derive(Subcommand)]
+pub enum Command {
+    /// Write the model the model options describe to a model file, to be used with --model
+    Build {
+        #[arg(value_name = "MODEL_FILE")]
+        output: PathBuf,
+    },
+    /// Write the topology of the model as a Graphviz DOT graph
+    Dot {
+        #[arg(value_name = "DOT_FILE")]
+        output: PathBuf,
+
+        /// Draw each pHMM as a single node labelled with its length
+        #[arg(long, default_value_t=false)]
+        collapse_phmms: bool,
+    },
+    /// Score each record against several competing models on their own and rank them, with the margin of each model over the next best one
+    Classify {
+        /// FASTA or FASTQ file
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Model files written by the build subcommand, named by their file names (default: a complex model per SVA subfamily, built from the model options)
+        #[arg(long, value_name = "MODEL_FILE", num_args = 1..)]
+        models: Vec<PathBuf>,
+
+        /// Score the models are ranked by
+        #[arg(long, value_enum, default_value_t=ClassifyScore::LogLikelihood)]
+        score: ClassifyScore,
+    },
+}
+
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
-
+    #[command(subcommand)]
+    pub command: Option<Command>,
     
-    #[arg(value_name = "FILE")]
-    pub file: PathBuf,
+    /// FASTA or FASTQ file
+    #[arg(value_name = "FILE", required = true)]
+    pub file: Option<PathBuf>,
     /// Output file
-    #[arg(short, long="output")]
+    #[arg(short, long="output", global = true)]
     pub output_file: Option<PathBuf>,
 
-    #[arg(short, long, default_value_t=1)]
+    #[arg(short, long, default_value_t=1, global = true)]
     pub cores: u8,
 
     /// Instead of the normal output write the full order state (note: this is primarily for testing purposes: the output file will get huge for a moderate number of input sequences)
@@ -40,11 +100,96 @@ pub struct Args {
     #[arg(long, default_value_t=false)]
     pub write_query_seq_state: bool,
 
-    #[arg(long, value_enum, default_value_t=SVAModelType::Simple)]
+    /// Write the total log-likelihood of each record under the model (forward algorithm) as an additional column
+    #[arg(long, default_value_t=false)]
+    pub write_log_likelihood: bool,
+
+    /// Write the mean posterior probability of each interval's region as an additional column
+    #[arg(long, default_value_t=false)]
+    pub write_confidence: bool,
+
+    /// Write log-odds scores in bits against the null model: one per interval, plus the whole record's viterbi and forward scores
+    #[arg(long, default_value_t=false)]
+    pub write_bit_score: bool,
+
+    /// Write the alignment of each motif copy (and Dfam consensus slice) to its motif as additional columns: an extended CIGAR, percent identity and the numbers of mismatches, insertions and deletions. Other regions get NA
+    #[arg(long, default_value_t=false)]
+    pub write_alignment: bool,
+
+    /// Write an ID for each interval and the ID of the region it's inside of (NA for the top level regions) as additional columns, so e.g. the hex copies can be grouped under their hexamer_region. IDs count up from 0 in each record
+    #[arg(long, default_value_t=false)]
+    pub write_parent_ids: bool,
+
+    /// Format of the interval output. The BED formats have the record ID as the chromosome, and leave out the optional columns, except that the BED score is the confidence (scaled to 0-1000) with --write-confidence. GFF3 always has the bit scores and the motif coordinates of the copies
+    #[arg(long, value_enum, default_value_t=OutputFormat::Tsv)]
+    pub format: OutputFormat,
+
+    /// .hmm file whose COMPO line is used as the null model composition (default: uniform)
+    #[arg(long, value_name = "HMM_FILE", global = true)]
+    pub null_composition: Option<PathBuf>,
+
+    /// Decoding algorithm used to find the state path
+    #[arg(long, value_enum, default_value_t=DecodeMethod::Viterbi)]
+    pub decode: DecodeMethod,
+
+    /// Memory (in MB) the viterbi traceback may use for a record before switching to the slower checkpointed version. Beam viterbi switches to exact viterbi when its traceback gets bigger than this
+    #[arg(long, default_value_t=DEFAULT_VITERBI_MEMORY_BUDGET >> 20)]
+    pub viterbi_memory_mb: usize,
+
+    /// Don't use the base qualities of FASTQ input. By default low quality bases count less toward matches and mismatches
+    #[arg(long, default_value_t=false, global = true)]
+    pub ignore_qualities: bool,
+
+    /// Also type the reverse complement of each record and keep whichever strand scores better, adding a strand column. Coordinates stay in the input orientation
+    #[arg(long, default_value_t=false)]
+    pub both_strands: bool,
+
+    /// Look at this many of the most likely paths and output the intervals of each one that has different intervals from the better paths, with the path rank and the score gap to the best path as additional columns. Only works with viterbi decoding
+    #[arg(long, default_value_t=1)]
+    pub k_best: usize,
+
+    /// Approximate viterbi: keep only this many emitting states per query position
+    #[arg(long)]
+    pub beam_width: Option<usize>,
+
+    /// Approximate viterbi: drop emitting states scoring this far (natural log) below the best at each query position
+    #[arg(long)]
+    pub beam_delta: Option<f64>,
+
+    #[arg(long, value_enum, default_value_t=SVAModelType::Simple, global = true)]
     pub sva_model: SVAModelType,
 
+    /// Model file written by the build subcommand, used instead of building the model from the model options
+    #[arg(
+        long,
+        value_name = "MODEL_FILE",
+        global = true,
+        conflicts_with_all = [
+            "sva_model", "train", "alignment_mode", "settings", "hex_motifs", "match_to_match", "match_to_ins",
+            "ins_extend", "del_extend", "loop_prob", "enter_skip_loop", "skip_to_skip", "match_emit_correct",
+            "min_hexamer_copies", "max_hexamer_copies", "min_vntr_copies", "max_vntr_copies",
+        ],
+    )]
+    pub model: Option<PathBuf>,
+
+    /// Instead of typing, fit the build parameters to the records in FILE (viterbi training) and write them to this settings file. Only works with the simple model
+    #[arg(long, value_name = "SETTINGS_FILE")]
+    pub train: Option<PathBuf>,
+
+    /// Maximum number of viterbi training iterations
+    #[arg(long, default_value_t=20)]
+    pub train_iterations: usize,
+
+    /// Whether pHMMs of a consensus have to be aligned end to end, or can match any part of it (for truncated elements). Applies to the models read from .hmm files (the ALU and SINE regions, also in the classify subcommand). The pHMMs of repeat motifs (the hexamer and VNTR copies) are always global
+    #[arg(long, value_enum, default_value_t=AlignmentMode::Global, help_heading = "HMM Build Parameters", global = true)]
+    pub alignment_mode: AlignmentMode,
+
+    /// Settings file written by --train. Replaces the individual build parameter options
+    #[arg(long, value_name = "SETTINGS_FILE", help_heading = "HMM Build Parameters", global = true)]
+    pub settings: Option<PathBuf>,
+
     /// Custom hexamer repeats with comma separated strings
-    #[arg(long, value_delimiter=',')]
+    #[arg(long, value_delimiter=',', global = true)]
     pub hex_motifs: Option<Vec<String>>,
     /// Probability of match state to match
     #[arg(
@@ -52,6 +197,7 @@ pub struct Args {
         default_value_t = HMMBuildSettings::default().match_to_match,
         value_parser=between_0_1_parser,
         help_heading = "HMM Build Parameters",
+        global = true,
     )]
     pub match_to_match: f64,
 
@@ -61,6 +207,7 @@ pub struct Args {
         default_value_t = HMMBuildSettings::default().match_to_ins,
         value_parser=between_0_1_parser,
         help_heading = "HMM Build Parameters",
+        global = true,
 
     )]
     pub match_to_ins: f64,
@@ -71,6 +218,7 @@ pub struct Args {
         default_value_t = HMMBuildSettings::default().ins_extend,
         value_parser=between_0_1_parser,
         help_heading = "HMM Build Parameters",
+        global = true,
 
     )]
     pub ins_extend: f64,
@@ -81,6 +229,7 @@ pub struct Args {
         default_value_t = HMMBuildSettings::default().del_extend,
         value_parser=between_0_1_parser,
         help_heading = "HMM Build Parameters",
+        global = true,
 
     )]
     pub del_extend: f64,
@@ -91,6 +240,7 @@ pub struct Args {
         default_value_t = HMMBuildSettings::default().loop_prob,
         value_parser=between_0_1_parser,
         help_heading = "HMM Build Parameters",
+        global = true,
 
     )]
     pub loop_prob: f64,
@@ -100,6 +250,7 @@ pub struct Args {
         default_value_t = HMMBuildSettings::default().enter_skip_loop,
         value_parser=between_0_1_parser,
         help_heading = "HMM Build Parameters",
+        global = true,
 
     )]
     pub enter_skip_loop: f64,
@@ -109,6 +260,7 @@ pub struct Args {
         default_value_t = HMMBuildSettings::default().skip_to_skip,
         value_parser=between_0_1_parser,
         help_heading = "HMM Build Parameters",
+        global = true,
 
     )]
     pub skip_to_skip: f64,
@@ -119,26 +271,78 @@ pub struct Args {
         default_value_t = HMMBuildSettings::default().match_emit_correct,
         value_parser=between_0_1_parser,
         help_heading = "HMM Build Parameters",
+        global = true,
 
     )]
     pub match_emit_correct: f64,
 
+    /// Minimum number of hexamer repeat copies in the SVA models
+    #[arg(long, default_value_t = 1, help_heading = "HMM Build Parameters", global = true)]
+    pub min_hexamer_copies: usize,
+
+    /// Maximum number of hexamer repeat copies in the SVA models (default: no maximum)
+    #[arg(long, help_heading = "HMM Build Parameters", global = true)]
+    pub max_hexamer_copies: Option<usize>,
+
+    /// Minimum number of VNTR repeat units in the SVA models
+    #[arg(long, default_value_t = 1, help_heading = "HMM Build Parameters", global = true)]
+    pub min_vntr_copies: usize,
+
+    /// Maximum number of VNTR repeat units in the SVA models (default: no maximum)
+    #[arg(long, help_heading = "HMM Build Parameters", global = true)]
+    pub max_vntr_copies: Option<usize>,
 }
 
 impl TryFrom<&Args> for HMMBuildSettings {
     type Error = HMMBuildError;
 
     fn try_from(value: &Args) -> std::result::Result<Self, Self::Error> {
-        HMMBuildSettings::new(
-            value.match_to_match,
-            value.match_to_ins,
-            value.ins_extend,
-            value.del_extend,
-            value.loop_prob,
-            value.enter_skip_loop,
-            value.skip_to_skip,
-            value.match_emit_correct,
-        )
+        let settings = match &value.settings {
+            Some(path) => HMMBuildSettings::read_settings(path)?,
+            None => HMMBuildSettings::new(
+                value.match_to_match,
+                value.match_to_ins,
+                value.ins_extend,
+                value.del_extend,
+                value.loop_prob,
+                value.enter_skip_loop,
+                value.skip_to_skip,
+                value.match_emit_correct,
+            )?,
+        };
+        Ok(settings
+            .with_alignment_mode(value.alignment_mode)
+            .with_hexamer_copies(CopyRange::new(value.min_hexamer_copies, value.max_hexamer_copies)?)
+            .with_vntr_copies(CopyRange::new(value.min_vntr_copies, value.max_vntr_copies)?))
+    }
+}
+
+impl From<&Args> for OutputColumns {
+    fn from(value: &Args) -> Self {
+        OutputColumns {
+            query_seq: value.write_query_seq_state,
+            confidence: value.write_confidence,
+            bits: value.write_bit_score,
+            log_likelihood: value.write_log_likelihood,
+            path_rank: value.k_best > 1,
+            strand: value.both_strands,
+            alignment: value.write_alignment,
+            parent_ids: value.write_parent_ids,
+        }
     }
 }
 
+impl Args {
+    /// Input records, which clap makes sure are given unless there's a subcommand
+    pub fn input_file(&self) -> &Path {
+        self.file.as_deref().expect("FILE is required without a subcommand")
+    }
+
+    /// Beam for approximate viterbi, if either beam option was given
+    pub fn beam(&self) -> Option<Beam> {
+        match (self.beam_width, self.beam_delta) {
+            (None, None) => None,
+            (width, delta) => Some(Beam { width, delta }),
+        }
+    }
+}
@@ -0,0 +1,1223 @@
+use std::collections::HashMap;
+use std::iter::zip;
+use rand::Rng;
+use crate::hmm::{HMM, StateAnnotation, StateRole};
+use crate::null_model::{NullModel, to_bits};
+use crate::utils::{AlignmentSummary, Interval, IntervalTree, QuerySeq, log_sum_exp, code_bases, N_CODES};
+use crate::hmm::{path_intervals, interval_steps, interval_parents, column_interval};
+use crate::simd::{PhmmLanes, SLOTS};
+
+/// Marks a cell with no previous state in the traceback
+const NO_STATE: u32 = u32::MAX;
+/// Default size of the full viterbi traceback matrix above which the checkpointed version is used
+pub const DEFAULT_VITERBI_MEMORY_BUDGET: usize = 1 << 30;
+
+/// Integer-indexed version of an ordered `HMM` for querying. Predecessors, successors and their log
+/// transition probabilities are stored as flat arrays with per-state offsets, and emissions as a
+/// dense states x codes table, so the DP inner loops never touch a string
+#[derive(Clone, Debug)]
+pub struct CompiledHmm {
+    identifiers: Vec<String>,
+    annotations: Vec<StateAnnotation>,
+    index_map: HashMap<String, usize>,
+    emits: Vec<bool>,
+    /// Log emission probability of each state for each query code (see `utils::code_bases`).
+    /// Silent states and N are 0
+    emissions: Vec<f64>,
+    pred_offsets: Vec<usize>,
+    pred_states: Vec<usize>,
+    pred_trans: Vec<f64>,
+    succ_offsets: Vec<usize>,
+    succ_states: Vec<usize>,
+    succ_trans: Vec<f64>,
+    start: usize,
+    end: usize,
+    /// Bytes the full traceback matrix may take before `query` switches to checkpointing
+    viterbi_memory_budget: usize,
+    /// Makes `query` use beam search when set
+    beam: Option<Beam>,
+    /// Match and insert states of the pHMM blocks, which exact viterbi scores with vector
+    /// instructions
+    phmm_lanes: PhmmLanes,
+    /// The other emitting states, which get the generic scalar code
+    scalar_emitting: Vec<usize>,
+    /// order_states puts every emitting state before the silent ones
+    first_silent: usize,
+    vectorised: bool,
+}
+
+/// Pruning for approximate viterbi. After the emitting states of a column are scored, only the
+/// best `width` of them, and only those within `delta` (natural log) of the best, are kept
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Beam {
+    pub width: Option<usize>,
+    pub delta: Option<f64>,
+}
+
+/// A path through the model and its log probability, e.g. one of `CompiledHmm::query_k_best`
+#[derive(Debug)]
+pub struct ScoredPath<'a> {
+    pub score: f64,
+    pub path: Vec<&'a str>,
+    pub query_indexes: Vec<usize>,
+}
+
+/// Output of forward-backward
+#[derive(Debug)]
+pub struct Posteriors {
+    /// states x (query length + 1), in probability space
+    pub probs: Vec<Vec<f64>>,
+    /// Total log-likelihood of the query, same as `CompiledHmm::forward`
+    pub log_likelihood: f64,
+}
+
+impl CompiledHmm {
+    /// States MUST be in order using hmm.order_states() beforehand, since the DP fills the states
+    /// of a column in the same order
+    pub fn new(hmm: &HMM) -> Self {
+        let index_map = hmm.get_index_map();
+        let n_states = hmm.states.len();
+
+        let mut emits = Vec::with_capacity(n_states);
+        let mut emissions = vec![0.0; n_states * N_CODES];
+        let mut pred_offsets = vec![0];
+        let mut pred_states = Vec::new();
+        let mut pred_trans = Vec::new();
+        let mut successors: Vec<Vec<(usize, f64)>> = vec![Vec::new(); n_states];
+
+        for (state_i, state) in hmm.states.iter().enumerate() {
+            emits.push(state.emits());
+            if let Some(emit_probs) = state.emission() {
+                // N stays at 0.0, i.e. it is always emitted, and the other ambiguity codes emit
+                // the marginal over the bases they allow
+                emissions[state_i * N_CODES..state_i * N_CODES + 4].copy_from_slice(&emit_probs[..4]);
+                for code in 5..N_CODES {
+                    emissions[state_i * N_CODES + code] = log_sum_exp(code_bases(code as u8).iter().map(|b| emit_probs[*b]));
+                }
+            }
+            for (prev_state, trans_lp) in zip(state.prev_states(), state.prev_state_transitions()) {
+                let prev_i = index_map[prev_state];
+                pred_states.push(prev_i);
+                pred_trans.push(*trans_lp);
+                successors[prev_i].push((state_i, *trans_lp));
+            }
+            pred_offsets.push(pred_states.len());
+        }
+
+        let mut succ_offsets = vec![0];
+        let mut succ_states = Vec::new();
+        let mut succ_trans = Vec::new();
+        for succ in successors {
+            for (next_i, trans_lp) in succ {
+                succ_states.push(next_i);
+                succ_trans.push(trans_lp);
+            }
+            succ_offsets.push(succ_states.len());
+        }
+
+        let first_silent = emits.iter().position(|e| !e).unwrap_or(n_states);
+        // The vector gathers index with i32
+        let lanes_fit = n_states * N_CODES <= i32::MAX as usize;
+        let (lane_states, scalar_emitting): (Vec<_>, Vec<_>) = (0..n_states)
+            .filter(|state_i| emits[*state_i])
+            .partition(|state_i| {
+                let range = pred_offsets[*state_i]..pred_offsets[*state_i + 1];
+                let is_phmm = matches!(hmm.states[*state_i].annotation().role, StateRole::Match | StateRole::Insert);
+                lanes_fit && is_phmm && range.len() <= SLOTS
+            });
+        let phmm_lanes = PhmmLanes::new(lane_states.into_iter()
+            .map(|state_i| {
+                let range = pred_offsets[state_i]..pred_offsets[state_i + 1];
+                let preds = zip(&pred_states[range.clone()], &pred_trans[range])
+                    .map(|(prev_i, trans_lp)| (*prev_i as u32, *trans_lp))
+                    .collect();
+                (state_i as u32, preds)
+            })
+            .collect());
+
+        CompiledHmm {
+            identifiers: hmm.states.iter().map(|s| s.identifier.clone()).collect(),
+            annotations: hmm.states.iter().map(|s| s.annotation().clone()).collect(),
+            start: index_map[hmm.get_start_states()[0]],
+            end: index_map[hmm.get_end_states()[0]],
+            index_map,
+            emits,
+            emissions,
+            pred_offsets,
+            pred_states,
+            pred_trans,
+            succ_offsets,
+            succ_states,
+            succ_trans,
+            viterbi_memory_budget: DEFAULT_VITERBI_MEMORY_BUDGET,
+            beam: None,
+            phmm_lanes,
+            scalar_emitting,
+            first_silent,
+            vectorised: true,
+        }
+    }
+
+    /// Makes `query` use the approximate beam search instead of exact viterbi
+    pub fn with_beam(mut self, beam: Beam) -> Self {
+        self.beam = Some(beam);
+        self
+    }
+
+    /// Turns the vectorised scoring of pHMM states in exact viterbi on or off (it is on by
+    /// default). The results are the same either way
+    pub fn with_vectorised(mut self, vectorised: bool) -> Self {
+        self.vectorised = vectorised;
+        self
+    }
+
+    /// Sets how many bytes the full viterbi traceback matrix may take before `query` switches to
+    /// the checkpointed version
+    pub fn with_viterbi_memory_budget(mut self, bytes: usize) -> Self {
+        self.viterbi_memory_budget = bytes;
+        self
+    }
+
+    pub fn n_states(&self) -> usize {
+        self.identifiers.len()
+    }
+
+    pub fn identifier(&self, state_i: usize) -> &str {
+        &self.identifiers[state_i]
+    }
+
+    pub fn state_index(&self, identifier: &str) -> Option<usize> {
+        self.index_map.get(identifier).copied()
+    }
+
+    pub fn annotation(&self, state_i: usize) -> &StateAnnotation {
+        &self.annotations[state_i]
+    }
+
+    /// Names of the regions intervals can have, each once, in the order of the states that start them
+    pub fn region_names(&self) -> Vec<&str> {
+        let mut names = vec![];
+        for annotation in self.annotations.iter().filter(|a| a.starts_interval()) {
+            if !names.contains(&annotation.region.as_str()) {
+                names.push(annotation.region.as_str());
+            }
+        }
+        names
+    }
+
+    fn path_annotations(&self, state_names: &[&str]) -> Vec<&StateAnnotation> {
+        state_names.iter().map(|s| &self.annotations[self.index_map[*s]]).collect()
+    }
+
+    fn preds(&self, state_i: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let range = self.pred_offsets[state_i]..self.pred_offsets[state_i + 1];
+        zip(self.pred_states[range.clone()].iter().copied(), self.pred_trans[range].iter().copied())
+    }
+
+    fn succs(&self, state_i: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let range = self.succ_offsets[state_i]..self.succ_offsets[state_i + 1];
+        zip(self.succ_states[range.clone()].iter().copied(), self.succ_trans[range].iter().copied())
+    }
+
+    /// Log emission probability of a state for the character before query column `col`
+    #[inline]
+    fn emission<Q: QuerySeq + ?Sized>(&self, state_i: usize, query: &Q, col: usize) -> f64 {
+        let code = query.codes()[col - 1];
+        let ln_em = self.emissions[state_i * N_CODES + code as usize];
+        match query.error_prob(col - 1) {
+            None => ln_em,
+            // A miscalled base is as likely to be any of them, so the ambiguity code's share of a
+            // uniform emission
+            Some(e) => ((1.0 - e) * ln_em.exp() + e * code_bases(code).len() as f64 / 4.0).ln(),
+        }
+    }
+
+    /// Generates a sequence from the model along with the state path that emitted it, in the same
+    /// form as `query` returns (identifiers and the query column of each state), so the path can
+    /// be used as ground truth, e.g. with `convert_to_intervals`
+    /// * `rng`:
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> (Vec<u8>, Vec<&str>, Vec<usize>) {
+        let mut sequence = Vec::new();
+        let mut path = vec![self.identifier(self.start)];
+        let mut query_indexes = vec![0];
+        let mut state_i = self.start;
+
+        while state_i != self.end {
+            state_i = pick(rng, self.succs(state_i))
+                .unwrap_or_else(|| panic!("State {} has no next state", self.identifier(state_i)));
+            if self.emits[state_i] {
+                let emissions = &self.emissions[state_i * N_CODES..state_i * N_CODES + 4];
+                let base = pick(rng, emissions.iter().copied().enumerate()).unwrap();
+                sequence.push(base as u8);
+            }
+            path.push(self.identifier(state_i));
+            query_indexes.push(sequence.len());
+        }
+        (sequence, path, query_indexes)
+    }
+
+    /// Most likely state path through the model. Returns the state identifiers and the query
+    /// column each state was at. Switches to `query_checkpointed` when the full traceback matrix
+    /// would be bigger than the memory budget, or uses `query_beam` if a beam has been set
+    /// * `query`:
+    pub fn query<Q: QuerySeq + ?Sized>(&self, query: &Q) -> (Vec<&str>, Vec<usize>) {
+        if let Some(beam) = &self.beam {
+            return self.query_beam(query, beam);
+        }
+        self.query_exact(query)
+    }
+
+    fn query_exact<Q: QuerySeq + ?Sized>(&self, query: &Q) -> (Vec<&str>, Vec<usize>) {
+        let trace_bytes = self.n_states() * (query.codes().len() + 1) * std::mem::size_of::<u32>();
+        if trace_bytes > self.viterbi_memory_budget {
+            self.query_checkpointed(query)
+        } else {
+            self.query_full(query)
+        }
+    }
+
+    /// Viterbi keeping the whole states x (query length + 1) traceback matrix
+    /// * `query`:
+    pub fn query_full<Q: QuerySeq + ?Sized>(&self, query: &Q) -> (Vec<&str>, Vec<usize>) {
+        let n_states = self.n_states();
+        let n_cols = query.codes().len() + 1;
+        let mut trace = vec![NO_STATE; n_states * n_cols];
+        let mut prev = vec![f64::NEG_INFINITY; n_states];
+        let mut cur = vec![f64::NEG_INFINITY; n_states];
+        for col in 0..n_cols {
+            std::mem::swap(&mut prev, &mut cur);
+            self.viterbi_column(query, col, &prev, &mut cur, &mut trace[col * n_states..(col + 1) * n_states]);
+        }
+        self.traceback(|col, state_i| trace[col * n_states + state_i], query.codes().len())
+    }
+
+    /// Viterbi with memory O(states * sqrt(query length)). The forward pass only keeps the scores
+    /// of the column before every sqrt(L)-sized segment. The traceback then recomputes the
+    /// traceback of one segment at a time from its checkpoint, moving left. The recomputed columns
+    /// are identical to the full matrix, so the path is exactly the same as `query_full`
+    /// * `query`:
+    pub fn query_checkpointed<Q: QuerySeq + ?Sized>(&self, query: &Q) -> (Vec<&str>, Vec<usize>) {
+        let n_states = self.n_states();
+        let n_cols = query.codes().len() + 1;
+        let seg_len = (n_cols as f64).sqrt().ceil() as usize;
+        let n_segs = n_cols.div_ceil(seg_len);
+
+        // checkpoints[j] holds the scores of the column right before segment j starts
+        let mut checkpoints = vec![vec![f64::NEG_INFINITY; n_states]];
+        let mut scratch_trace = vec![NO_STATE; n_states];
+        let mut prev = vec![f64::NEG_INFINITY; n_states];
+        let mut cur = vec![f64::NEG_INFINITY; n_states];
+        for col in 0..n_cols {
+            std::mem::swap(&mut prev, &mut cur);
+            self.viterbi_column(query, col, &prev, &mut cur, &mut scratch_trace);
+            if (col + 1) % seg_len == 0 && col + 1 < n_cols {
+                checkpoints.push(cur.clone());
+            }
+        }
+
+        let mut loaded_seg = n_segs;
+        let mut seg_trace = vec![NO_STATE; n_states * seg_len];
+        self.traceback(|col, state_i| {
+            let seg = col / seg_len;
+            if seg != loaded_seg {
+                let seg_start = seg * seg_len;
+                prev.copy_from_slice(&checkpoints[seg]);
+                for seg_col in seg_start..((seg + 1) * seg_len).min(n_cols) {
+                    let offset = (seg_col - seg_start) * n_states;
+                    self.viterbi_column(query, seg_col, &prev, &mut cur, &mut seg_trace[offset..offset + n_states]);
+                    std::mem::swap(&mut prev, &mut cur);
+                }
+                loaded_seg = seg;
+            }
+            seg_trace[(col - seg * seg_len) * n_states + state_i]
+        }, query.codes().len())
+    }
+
+    /// Approximate viterbi that only extends the states that survive the beam in each column, so
+    /// hopeless states (e.g. most of the Dfam match states) are never scored. Only the traceback of
+    /// the scored states is kept. Falls back to exact viterbi if the beam loses every path to the
+    /// end state, or if its traceback grows bigger than the memory budget
+    /// * `query`:
+    /// * `beam`:
+    pub fn query_beam<Q: QuerySeq + ?Sized>(&self, query: &Q, beam: &Beam) -> (Vec<&str>, Vec<usize>) {
+        let n_states = self.n_states();
+        let n_cols = query.codes().len() + 1;
+
+        // The (state, previous state) traceback of the scored states, sorted by state within each
+        // column, and where each column's starts
+        let mut trace: Vec<(u32, u32)> = Vec::new();
+        let mut col_offsets = vec![0];
+        let mut prev = vec![f64::NEG_INFINITY; n_states];
+        let mut cur = vec![f64::NEG_INFINITY; n_states];
+        let mut marked = vec![false; n_states];
+        // States with a score in the previous column, and in the one before that
+        let mut active: Vec<usize> = Vec::new();
+        let mut stale: Vec<usize> = Vec::new();
+        let mut emitting: Vec<usize> = Vec::new();
+
+        for col in 0..n_cols {
+            std::mem::swap(&mut prev, &mut cur);
+            for state_i in stale.iter() {
+                cur[*state_i] = f64::NEG_INFINITY;
+            }
+
+            // Emitting states that follow a surviving state of the previous column
+            emitting.clear();
+            if col > 0 {
+                for prev_i in active.iter() {
+                    for (next_i, _) in self.succs(*prev_i) {
+                        if self.emits[next_i] && !marked[next_i] {
+                            marked[next_i] = true;
+                            emitting.push(next_i);
+                        }
+                    }
+                }
+            }
+            for state_i in emitting.iter() {
+                marked[*state_i] = false;
+                let ln_em = self.emission(*state_i, query, col);
+                let (score, best_state) = self.best_pred(*state_i, &prev, ln_em);
+                cur[*state_i] = score;
+                trace.push((*state_i as u32, best_state));
+            }
+            let threshold = self.prune(&mut emitting, &mut cur, beam);
+
+            // Silent states reachable from the survivors, in topological order. These are held to
+            // the same threshold, otherwise the deletion chains would reach the whole model, except
+            // in the last column so that every surviving path can still get to the end state
+            stale.clear();
+            if col == 0 {
+                cur[self.start] = 0.0;
+                stale.push(self.start);
+            }
+            stale.extend(emitting.iter().copied());
+            for state_i in stale.iter() {
+                self.mark_silent_succs(*state_i, &mut marked);
+            }
+            for state_i in self.first_silent..n_states {
+                if !marked[state_i] {
+                    continue;
+                }
+                marked[state_i] = false;
+                let (score, best_state) = self.best_pred(state_i, &cur, 0.0);
+                if best_state != NO_STATE && (score >= threshold || col == query.codes().len()) {
+                    cur[state_i] = score;
+                    trace.push((state_i as u32, best_state));
+                    stale.push(state_i);
+                    self.mark_silent_succs(state_i, &mut marked);
+                }
+            }
+            std::mem::swap(&mut active, &mut stale);
+
+            let col_start = col_offsets[col];
+            trace[col_start..].sort_unstable_by_key(|(state_i, _)| *state_i);
+            col_offsets.push(trace.len());
+            let trace_bytes = trace.len() * std::mem::size_of::<(u32, u32)>() + col_offsets.len() * std::mem::size_of::<usize>();
+            if trace_bytes > self.viterbi_memory_budget {
+                return self.query_exact(query);
+            }
+        }
+
+        if cur[self.end] == f64::NEG_INFINITY {
+            return self.query_exact(query);
+        }
+        self.traceback(|col, state_i| {
+            let col_trace = &trace[col_offsets[col]..col_offsets[col + 1]];
+            col_trace.binary_search_by_key(&(state_i as u32), |(s, _)| *s)
+                .map_or(NO_STATE, |i| col_trace[i].1)
+        }, query.codes().len())
+    }
+
+    /// Best score for a state over its predecessors in `scores`, and which predecessor it came from
+    #[inline]
+    fn best_pred(&self, state_i: usize, scores: &[f64], ln_em: f64) -> (f64, u32) {
+        let mut best_state = NO_STATE;
+        let mut best_score = f64::NEG_INFINITY;
+        for (prev_i, trans_lp) in self.preds(state_i) {
+            let score = scores[prev_i] + trans_lp + ln_em;
+            if score > best_score {
+                best_score = score;
+                best_state = prev_i as u32;
+            }
+        }
+        (best_score, best_state)
+    }
+
+    fn mark_silent_succs(&self, state_i: usize, marked: &mut [bool]) {
+        for (next_i, _) in self.succs(state_i) {
+            if !self.emits[next_i] {
+                marked[next_i] = true;
+            }
+        }
+    }
+
+    /// Drops the states outside of the beam from `states`, resetting their scores. Returns the
+    /// lowest score that was kept
+    fn prune(&self, states: &mut Vec<usize>, scores: &mut [f64], beam: &Beam) -> f64 {
+        states.retain(|s| scores[*s] > f64::NEG_INFINITY);
+        let mut threshold = f64::NEG_INFINITY;
+        if let Some(delta) = beam.delta {
+            let best = states.iter().map(|s| scores[*s]).fold(f64::NEG_INFINITY, f64::max);
+            threshold = best - delta;
+        }
+        if let Some(width) = beam.width.filter(|w| *w > 0 && *w < states.len()) {
+            let mut sorted = states.iter().map(|s| scores[*s]).collect::<Vec<_>>();
+            let (_, kth, _) = sorted.select_nth_unstable_by(width - 1, |a, b| b.total_cmp(a));
+            threshold = threshold.max(*kth);
+        }
+        states.retain(|s| {
+            let keep = scores[*s] >= threshold;
+            if !keep {
+                scores[*s] = f64::NEG_INFINITY;
+            }
+            keep
+        });
+        threshold
+    }
+
+    /// The `k` most likely distinct state paths, best first (list viterbi). Every cell keeps its
+    /// `k` best scores along with the previous state and which of its ranks they came from, so
+    /// this needs `k` times the memory of `query_full` and ignores the memory budget and beam.
+    /// The first path is always the same as `query_full`
+    /// * `query`:
+    /// * `k`:
+    pub fn query_k_best<Q: QuerySeq + ?Sized>(&self, query: &Q, k: usize) -> Vec<ScoredPath<'_>> {
+        let n_states = self.n_states();
+        let n_cols = query.codes().len() + 1;
+        // Scores of a state are sorted best first, with -inf for ranks that don't exist
+        let mut prev = vec![f64::NEG_INFINITY; n_states * k];
+        let mut cur = vec![f64::NEG_INFINITY; n_states * k];
+        let mut trace = vec![(NO_STATE, 0_u32); n_states * n_cols * k];
+        let mut candidates: Vec<(f64, u32, u32)> = Vec::new();
+
+        for col in 0..n_cols {
+            std::mem::swap(&mut prev, &mut cur);
+            cur.fill(f64::NEG_INFINITY);
+            if col == 0 && k > 0 {
+                cur[self.start * k] = 0.0;
+            }
+            for state_i in 0..n_states {
+                let (scores, ln_em) = if self.emits[state_i] {
+                    if col == 0 {
+                        continue;
+                    }
+                    (&prev, self.emission(state_i, query, col))
+                } else {
+                    (&cur, 0.0)
+                };
+                candidates.clear();
+                for (prev_i, trans_lp) in self.preds(state_i) {
+                    for rank in 0..k {
+                        let score = scores[prev_i * k + rank] + trans_lp + ln_em;
+                        if score == f64::NEG_INFINITY {
+                            break;
+                        }
+                        candidates.push((score, prev_i as u32, rank as u32));
+                    }
+                }
+                // The sort is stable, so ties go to the first predecessor like in viterbi_column
+                candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+                let cell = (col * n_states + state_i) * k;
+                for (rank, (score, prev_i, prev_rank)) in candidates.iter().take(k).enumerate() {
+                    cur[state_i * k + rank] = *score;
+                    trace[cell + rank] = (*prev_i, *prev_rank);
+                }
+            }
+        }
+
+        (0..k)
+            .take_while(|rank| cur[self.end * k + rank] > f64::NEG_INFINITY)
+            .map(|rank| {
+                let mut state_i = self.end;
+                let mut rank_i = rank;
+                let mut col = query.codes().len();
+                let mut path = Vec::new();
+                let mut query_indexes = Vec::new();
+                while state_i != self.start {
+                    path.push(self.identifiers[state_i].as_str());
+                    query_indexes.push(col);
+                    let (prev_i, prev_rank) = trace[(col * n_states + state_i) * k + rank_i];
+                    if self.emits[state_i] {
+                        col -= 1;
+                    }
+                    state_i = prev_i as usize;
+                    rank_i = prev_rank as usize;
+                }
+                path.push(self.identifiers[self.start].as_str());
+                query_indexes.push(col);
+                path.reverse();
+                query_indexes.reverse();
+                ScoredPath { score: cur[self.end * k + rank], path, query_indexes }
+            })
+            .collect()
+    }
+
+    /// Fills in the viterbi scores and traceback of one column from the scores of the previous one
+    fn viterbi_column<Q: QuerySeq + ?Sized>(&self, query: &Q, col: usize, prev: &[f64], cur: &mut [f64], trace: &mut [u32]) {
+        cur.fill(f64::NEG_INFINITY);
+        if col == 0 {
+            cur[self.start] = 0.0;
+        }
+        // The lanes use the emission table directly, so they can't mix in base qualities
+        let use_lanes = self.vectorised && col > 0 && !self.phmm_lanes.is_empty() && query.error_prob(col - 1).is_none();
+        // Emitting states come first and the silent states are topologically sorted by
+        // order_states, so every predecessor in the same column is already filled in
+        if use_lanes {
+            self.phmm_lanes.viterbi_column(&self.emissions, query.codes()[col - 1], prev, cur, trace);
+            for state_i in self.scalar_emitting.iter().copied().chain(self.first_silent..self.n_states()) {
+                self.viterbi_cell(query, col, state_i, prev, cur, trace);
+            }
+        } else {
+            for state_i in 0..self.n_states() {
+                self.viterbi_cell(query, col, state_i, prev, cur, trace);
+            }
+        }
+    }
+
+    /// Viterbi score and traceback of one state in a column
+    #[inline]
+    fn viterbi_cell<Q: QuerySeq + ?Sized>(&self, query: &Q, col: usize, state_i: usize, prev: &[f64], cur: &mut [f64], trace: &mut [u32]) {
+        let (ln_em, emitting) = if self.emits[state_i] {
+            if col == 0 {
+                // col = 0 means before first character
+                return;
+            }
+            (self.emission(state_i, query, col), true)
+        } else {
+            (0.0, false)
+        };
+        let mut best_state = NO_STATE;
+        let mut best_score = f64::NEG_INFINITY;
+        for (prev_i, trans_lp) in self.preds(state_i) {
+            let prev_score = if emitting { prev[prev_i] } else { cur[prev_i] };
+            let score = prev_score + trans_lp + ln_em;
+            if score > best_score {
+                best_score = score;
+                best_state = prev_i as u32;
+            }
+        }
+        if best_state != NO_STATE {
+            cur[state_i] = best_score;
+        }
+        trace[state_i] = best_state;
+    }
+
+    /// Follows the traceback from the end state at the last column back to the start state
+    /// * `trace`: previous state of a state at a column
+    /// * `last_col`:
+    fn traceback(&self, mut trace: impl FnMut(usize, usize) -> u32, last_col: usize) -> (Vec<&str>, Vec<usize>) {
+        let mut state_i = self.end;
+        let mut col = last_col;
+        let mut path = Vec::new();
+        let mut query_indexes = Vec::new();
+
+        while state_i != self.start {
+            path.push(self.identifiers[state_i].as_str());
+            query_indexes.push(col);
+            let prev_i = trace(col, state_i);
+            if prev_i == NO_STATE {
+                panic!("No previous state found for {}", self.identifiers[state_i]);
+            }
+            if self.emits[state_i] {
+                col -= 1;
+            }
+            state_i = prev_i as usize;
+        }
+        path.push(self.identifiers[self.start].as_str());
+        query_indexes.push(col);
+        path.reverse();
+        query_indexes.reverse();
+        (path, query_indexes)
+    }
+
+    /// Total log-likelihood of the query summed over every path through the model (forward
+    /// algorithm)
+    /// * `query`:
+    pub fn forward<Q: QuerySeq + ?Sized>(&self, query: &Q) -> f64 {
+        let mut prev = vec![f64::NEG_INFINITY; self.n_states()];
+        let mut cur = vec![f64::NEG_INFINITY; self.n_states()];
+        for col in 0..(query.codes().len() + 1) {
+            std::mem::swap(&mut prev, &mut cur);
+            self.forward_column(query, col, &prev, &mut cur);
+        }
+        cur[self.end]
+    }
+
+    fn forward_column<Q: QuerySeq + ?Sized>(&self, query: &Q, col: usize, prev: &[f64], cur: &mut [f64]) {
+        cur.fill(f64::NEG_INFINITY);
+        if col == 0 {
+            cur[self.start] = 0.0;
+        }
+        for state_i in 0..self.n_states() {
+            if state_i == self.start {
+                continue;
+            }
+            if self.emits[state_i] {
+                if col == 0 {
+                    continue;
+                }
+                let ln_em = self.emission(state_i, query, col);
+                cur[state_i] = log_sum_exp(self.preds(state_i).map(|(p, t)| prev[p] + t)) + ln_em;
+            } else {
+                cur[state_i] = log_sum_exp(self.preds(state_i).map(|(p, t)| cur[p] + t));
+            }
+        }
+    }
+
+    /// Posterior probability of every state at every query column (forward-backward). Rows follow
+    /// the order of the states and columns are the same as the viterbi matrices, so column 0 is
+    /// before the first character
+    /// * `query`:
+    pub fn posteriors<Q: QuerySeq + ?Sized>(&self, query: &Q) -> Posteriors {
+        let n_states = self.n_states();
+        let n_cols = query.codes().len() + 1;
+
+        let mut f_cols = vec![vec![f64::NEG_INFINITY; n_states]; n_cols];
+        for col in 0..n_cols {
+            let (done, rest) = f_cols.split_at_mut(col);
+            let prev = done.last().map(|c| c.as_slice()).unwrap_or(&[]);
+            self.forward_column(query, col, prev, &mut rest[0]);
+        }
+        let log_likelihood = f_cols[query.codes().len()][self.end];
+
+        let mut b_cols = vec![vec![f64::NEG_INFINITY; n_states]; n_cols];
+        for col in (0..n_cols).rev() {
+            let (cur, rest) = b_cols.split_at_mut(col + 1);
+            let next = rest.first().map(|c| c.as_slice()).unwrap_or(&[]);
+            self.backward_column(query, col, next, &mut cur[col]);
+        }
+
+        let mut probs = vec![vec![0.0; n_cols]; n_states];
+        for col in 0..n_cols {
+            for state_i in 0..n_states {
+                probs[state_i][col] = (f_cols[col][state_i] + b_cols[col][state_i] - log_likelihood).exp();
+            }
+        }
+        Posteriors { probs, log_likelihood }
+    }
+
+    /// Backward probabilities of one column given the next one
+    fn backward_column<Q: QuerySeq + ?Sized>(&self, query: &Q, col: usize, next: &[f64], cur: &mut [f64]) {
+        cur.fill(f64::NEG_INFINITY);
+        let last_col = col == query.codes().len();
+        if last_col {
+            cur[self.end] = 0.0;
+        }
+        // Reverse of the forward order: silent states are at the back in topological order, so
+        // their successors in the same column have already been filled in
+        for state_i in (0..self.n_states()).rev() {
+            if last_col && state_i == self.end {
+                continue;
+            }
+            cur[state_i] = log_sum_exp(self.succs(state_i).filter_map(|(next_i, trans_lp)| {
+                if !self.emits[next_i] {
+                    Some(trans_lp + cur[next_i])
+                } else if last_col {
+                    // Nothing left to emit
+                    None
+                } else {
+                    Some(trans_lp + self.emission(next_i, query, col + 1) + next[next_i])
+                }
+            }));
+        }
+    }
+
+    /// Posterior decoding: the valid path through the model that maximises the product of the
+    /// posterior probabilities of its states (posterior-viterbi), as an alternative to the viterbi
+    /// path. Silent states count too, so the path can't wander through unlikely silent states for
+    /// free
+    /// * `query`:
+    pub fn query_posterior<Q: QuerySeq + ?Sized>(&self, query: &Q) -> (Vec<&str>, Vec<usize>) {
+        let posteriors = self.posteriors(query);
+        self.query_posterior_with(&posteriors)
+    }
+
+    /// Same as `query_posterior` when the posteriors have already been calculated
+    /// * `posteriors`:
+    pub fn query_posterior_with(&self, posteriors: &Posteriors) -> (Vec<&str>, Vec<usize>) {
+        let n_states = self.n_states();
+        let probs = &posteriors.probs;
+        let n_cols = probs[0].len();
+
+        // Log of the product of posteriors along the best path to each state
+        let mut gain = vec![f64::NEG_INFINITY; n_states * n_cols];
+        let mut trace = vec![NO_STATE; n_states * n_cols];
+        gain[self.start] = probs[self.start][0].ln();
+        for col in 0..n_cols {
+            for state_i in 0..n_states {
+                let shift = match self.emits[state_i] {
+                    false => 0,
+                    true if col == 0 => continue,
+                    true => 1,
+                };
+                let mut best_gain = f64::NEG_INFINITY;
+                for (prev_i, trans_lp) in self.preds(state_i) {
+                    // Only follow transitions the model actually allows
+                    if trans_lp == f64::NEG_INFINITY {
+                        continue;
+                    }
+                    let prev_gain = gain[(col - shift) * n_states + prev_i];
+                    if prev_gain > best_gain {
+                        best_gain = prev_gain;
+                        trace[col * n_states + state_i] = prev_i as u32;
+                    }
+                }
+                if best_gain > f64::NEG_INFINITY {
+                    gain[col * n_states + state_i] = best_gain + probs[state_i][col].ln();
+                }
+            }
+        }
+        self.traceback(|col, state_i| trace[col * n_states + state_i], n_cols - 1)
+    }
+
+    /// Outputs 0-based intervals for the regions a path from this model goes through, using the
+    /// state annotations
+    /// * `state_names`:
+    /// * `state_pos`:
+    pub fn convert_to_intervals(&self, state_names: Vec<&str>, state_pos: Vec<usize>) -> Vec<(&str, Interval)> {
+        path_intervals(&self.path_annotations(&state_names), &state_pos)
+    }
+
+    /// Index of the interval each interval of the path (as from `convert_to_intervals`) is
+    /// directly inside of, e.g. `hexamer_region` for a `hex` copy. None for the top level regions
+    /// * `state_names`:
+    pub fn interval_parents(&self, state_names: &[&str]) -> Vec<Option<usize>> {
+        interval_parents(&self.path_annotations(state_names))
+    }
+
+    /// Same as `convert_to_intervals`, with the intervals arranged by how their regions nest
+    /// * `state_names`:
+    /// * `state_pos`:
+    pub fn interval_tree(&self, state_names: Vec<&str>, state_pos: Vec<usize>) -> IntervalTree<'_> {
+        IntervalTree::new(self.convert_to_intervals(state_names.clone(), state_pos), &self.interval_parents(&state_names))
+    }
+
+    /// Same as `convert_to_intervals`, but also fills in the confidence of each interval: the mean
+    /// posterior probability that the positions in its span belong to the region's states. The
+    /// region's states are everything reachable from its start state without passing one of the
+    /// region's end states. A region that's never closed is averaged up to the end of the query
+    /// * `state_names`:
+    /// * `state_pos`:
+    /// * `posteriors`:
+    pub fn convert_to_intervals_with_confidence(
+        &self,
+        state_names: Vec<&str>,
+        state_pos: Vec<usize>,
+        posteriors: &Posteriors,
+    ) -> Vec<(&str, Interval)> {
+        let mut region_states: HashMap<usize, Vec<usize>> = HashMap::new();
+        let last_col = posteriors.probs.first().map_or(0, |p| p.len() - 1);
+
+        interval_steps(&self.path_annotations(&state_names))
+            .into_iter()
+            .map(|(name, start, stop)| {
+                let start_col = state_pos[start];
+                let stop_col = if stop == usize::MAX { usize::MAX } else { state_pos[stop] };
+                let start_i = self.index_map[state_names[start]];
+                let members = region_states.entry(start_i).or_insert_with(|| self.region_members(start_i));
+                let span_end = stop_col.min(last_col);
+                let confidence = if span_end > start_col {
+                    ((start_col + 1)..=span_end)
+                        .map(|col| members.iter().map(|i| posteriors.probs[*i][col]).sum::<f64>())
+                        .sum::<f64>()
+                        / (span_end - start_col) as f64
+                } else {
+                    posteriors.probs[start_i][start_col]
+                };
+                let mut interval = column_interval(start_col, stop_col);
+                interval.confidence = Some(confidence);
+                (name, interval)
+            })
+            .collect()
+    }
+
+    /// Emitting states reachable from start_i without passing through an end state of its region
+    fn region_members(&self, start_i: usize) -> Vec<usize> {
+        let region = &self.annotations[start_i].region;
+        let is_end = |i: usize| self.annotations[i].role == StateRole::End && self.annotations[i].region == *region;
+        let mut seen = vec![false; self.n_states()];
+        let mut stack = vec![start_i];
+        seen[start_i] = true;
+        while let Some(state_i) = stack.pop() {
+            for (next_i, _) in self.succs(state_i) {
+                if !is_end(next_i) && !seen[next_i] {
+                    seen[next_i] = true;
+                    stack.push(next_i);
+                }
+            }
+        }
+        (0..self.n_states())
+            .filter(|i| seen[*i] && self.emits[*i])
+            .collect()
+    }
+
+    /// Log probability of the path up to and including each of its steps. The path should come
+    /// from one of the decoding methods on this model
+    /// * `query`:
+    /// * `state_names`:
+    /// * `state_pos`:
+    pub fn path_scores<Q: QuerySeq + ?Sized>(&self, query: &Q, state_names: &[&str], state_pos: &[usize]) -> Vec<f64> {
+        let mut scores = Vec::with_capacity(state_names.len());
+        let mut total = 0.0;
+        scores.push(total);
+        for step in 1..state_names.len() {
+            let state_i = self.index_map[state_names[step]];
+            let prev_i = self.index_map[state_names[step - 1]];
+            let trans_lp = self.preds(state_i)
+                .find(|(p, _)| *p == prev_i)
+                .map(|(_, t)| t)
+                .unwrap_or_else(|| panic!("{} does not follow {}", state_names[step], state_names[step - 1]));
+            let ln_em = match self.emits[state_i] {
+                true => self.emission(state_i, query, state_pos[step]),
+                false => 0.0,
+            };
+            total += trans_lp + ln_em;
+            scores.push(total);
+        }
+        scores
+    }
+
+    /// Fills in the log-odds score (in bits) of each interval against the null model, using the
+    /// part of the path score between the region's start and end states. `intervals` has to come
+    /// from the same path, e.g. via `convert_to_intervals`
+    /// * `intervals`:
+    /// * `query`:
+    /// * `state_names`:
+    /// * `state_pos`:
+    /// * `null_model`:
+    pub fn score_intervals<Q: QuerySeq + ?Sized>(
+        &self,
+        intervals: &mut [(&str, Interval)],
+        query: &Q,
+        state_names: &[&str],
+        state_pos: &[usize],
+        null_model: &NullModel,
+    ) {
+        let scores = self.path_scores(query, state_names, state_pos);
+        for ((_, interval), (_, start, stop)) in zip(intervals.iter_mut(), interval_steps(&self.path_annotations(state_names))) {
+            if stop == usize::MAX {
+                continue;
+            }
+            let null_score = null_model.span_log_likelihood(query.codes(), state_pos[start], state_pos[stop]);
+            interval.bits = Some(to_bits(scores[stop] - scores[start] - null_score));
+        }
+    }
+
+    /// Fills in the alignment of each pHMM interval (a motif copy or a slice of a consensus) to its
+    /// motif from the match, insert and delete states along the path. A match state's base matches
+    /// if it's the most likely one the state emits. The alignment's target is the range of motif
+    /// positions it covers. `intervals` has to come from the same path, e.g. via
+    /// `convert_to_intervals`
+    /// * `intervals`:
+    /// * `query`:
+    /// * `state_names`:
+    /// * `state_pos`:
+    pub fn align_intervals<Q: QuerySeq + ?Sized>(
+        &self,
+        intervals: &mut [(&str, Interval)],
+        query: &Q,
+        state_names: &[&str],
+        state_pos: &[usize],
+    ) {
+        let annotations = self.path_annotations(state_names);
+        for ((_, interval), (_, start, stop)) in zip(intervals.iter_mut(), interval_steps(&annotations)) {
+            if stop == usize::MAX || annotations[start].motif.is_none() {
+                continue;
+            }
+            let ops = (start + 1..stop).filter_map(|step| match annotations[step].role {
+                StateRole::Match => {
+                    let code = query.codes()[state_pos[step] - 1];
+                    Some(if code == self.consensus_base(self.index_map[state_names[step]]) { '=' } else { 'X' })
+                }
+                StateRole::Insert => Some('I'),
+                StateRole::Delete => Some('D'),
+                _ => None,
+            });
+            let mut alignment = AlignmentSummary::from_ops(ops);
+            let mut positions = (start + 1..stop)
+                .filter(|step| matches!(annotations[*step].role, StateRole::Match | StateRole::Delete))
+                .filter_map(|step| annotations[step].position);
+            alignment.target = positions.next().map(|first| (first, positions.next_back().unwrap_or(first) + 1));
+            interval.alignment = Some(alignment);
+        }
+    }
+
+    /// Most likely base of an emitting state
+    fn consensus_base(&self, state_i: usize) -> u8 {
+        let emission = &self.emissions[state_i * N_CODES..state_i * N_CODES + 4];
+        (0..4).max_by(|a, b| emission[*a].total_cmp(&emission[*b])).unwrap() as u8
+    }
+}
+
+/// Draws one of the items according to their log probabilities
+fn pick<R: Rng>(rng: &mut R, items: impl Iterator<Item = (usize, f64)>) -> Option<usize> {
+    let mut u = rng.random::<f64>();
+    let mut last = None;
+    for (item, lp) in items {
+        if lp == f64::NEG_INFINITY {
+            continue;
+        }
+        u -= lp.exp();
+        if u < 0.0 {
+            return Some(item);
+        }
+        last = Some(item);
+    }
+    // Only reached if the probabilities sum to slightly less than 1
+    last
+}
+
+impl From<&HMM> for CompiledHmm {
+    fn from(value: &HMM) -> Self {
+        CompiledHmm::new(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Beam, NO_STATE};
+    use crate::builder::*;
+    use crate::hmm::StateRole;
+    use crate::utils::*;
+
+    #[test]
+    fn forward_test() {
+        let settings = HMMBuildSettings::default();
+        let hmm = create_skip_state(&settings, Some("test"));
+        // Only one path through a skip state, so the forward sum is just that path
+        let query = sequence_to_bytes("ACGTA");
+        let expected = 5.0 * 0.25_f64.ln() + 4.0 * settings.skip_to_skip.ln() + (1.0 - settings.skip_to_skip).ln();
+        assert!((hmm.forward(&query) - expected).abs() < 1e-9);
+
+        let hmm = create_pHMM(&sequence_to_bytes("ACGTACGT"), &settings, Some("test"));
+        assert!(hmm.forward(&sequence_to_bytes("ACGTACGT")) > hmm.forward(&sequence_to_bytes("TTGCATGA")));
+    }
+
+    #[test]
+    fn posterior_test() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let hmm = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2"], &settings, "test");
+        let query = sequence_to_bytes(&format!("{}{}{}", motifs[0], motifs[1], motifs[1]));
+
+        let posteriors = hmm.posteriors(&query);
+        assert!((posteriors.log_likelihood - hmm.forward(&query)).abs() < 1e-9);
+        // Exactly one emitting state accounts for each character
+        for col in 1..=query.len() {
+            let total = hmm.states.iter().enumerate()
+                .filter(|(_, s)| s.emits())
+                .map(|(i, _)| posteriors.probs[i][col])
+                .sum::<f64>();
+            assert!((total - 1.0).abs() < 1e-6, "column {col} sums to {total}");
+        }
+
+        let compiled = hmm.compile();
+        let (path, query_indexes) = compiled.query_posterior_with(&posteriors);
+        assert_eq!((path.clone(), query_indexes.clone()), hmm.query(&query));
+        let intervals = compiled.convert_to_intervals_with_confidence(path, query_indexes, &posteriors);
+        assert_eq!(intervals.iter().filter(|(n, _)| *n == "Rep2").count(), 2);
+        for (_, interval) in intervals.iter() {
+            let confidence = interval.confidence.unwrap();
+            assert!((0.0..=1.0 + 1e-9).contains(&confidence));
+        }
+    }
+
+    #[test]
+    fn checkpointed_matches_full() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG", "GAAGCTACT"];
+        let hmm = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2", "Rep3"], &settings, "test");
+        let compiled = hmm.compile();
+        let full_seq = format!(
+            "{}{}{}{}{}{}", motifs[0], motifs[0], "ATGATCGATTTGTAAACTACTGGGACCCTGT", motifs[1], motifs[2], motifs[1]
+        );
+        // Cover lengths that do and don't line up with the segment boundaries
+        for len in [1, 2, 8, 15, 16, 17, full_seq.len()] {
+            let query = sequence_to_bytes(&full_seq[..len]);
+            assert_eq!(compiled.query_full(&query), compiled.query_checkpointed(&query), "length {len}");
+        }
+
+        let query = sequence_to_bytes(&full_seq);
+        let limited = hmm.compile().with_viterbi_memory_budget(0);
+        assert_eq!(limited.query(&query), compiled.query_full(&query));
+    }
+
+    #[test]
+    fn k_best_paths() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG", "GAAGCTACT"];
+        let hmm = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2", "Rep3"], &settings, "test");
+        let compiled = hmm.compile();
+        let query = sequence_to_bytes(&format!("{}{}GAAGCTAGT", motifs[0], motifs[1]));
+
+        let paths = compiled.query_k_best(&query, 5);
+        assert_eq!(paths.len(), 5);
+        let (best_path, best_indexes) = compiled.query_full(&query);
+        assert_eq!(paths[0].path, best_path);
+        assert_eq!(paths[0].query_indexes, best_indexes);
+        for (i, p) in paths.iter().enumerate() {
+            // Scores are the real path scores, in order, and the paths are all different
+            let score = *compiled.path_scores(&query, &p.path, &p.query_indexes).last().unwrap();
+            assert!((score - p.score).abs() < 1e-9);
+            assert!(paths[i + 1..].iter().all(|q| q.score <= p.score && q.path != p.path));
+        }
+
+        // The same paths as intervals
+        fn bounds(intervals: Vec<(&str, Interval)>) -> Vec<(&str, usize, usize)> {
+            intervals.into_iter().map(|(n, i)| (n, i.start, i.stop)).collect()
+        }
+        let interval_paths = hmm.query_k_best(&query, 5);
+        assert_eq!(interval_paths.len(), 5);
+        for ((score, intervals), p) in interval_paths.into_iter().zip(paths) {
+            assert_eq!(score, p.score);
+            assert_eq!(bounds(intervals), bounds(compiled.convert_to_intervals(p.path, p.query_indexes)));
+        }
+    }
+
+    #[test]
+    fn unpruned_beam_matches_full() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG", "GAAGCTACT"];
+        let hmm = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2", "Rep3"], &settings, "test");
+        let compiled = hmm.compile();
+        let query = sequence_to_bytes(&format!(
+            "{}{}{}{}{}", motifs[0], motifs[0], "ATGATCGATTTGTAAACTACT", motifs[1], motifs[2]
+        ));
+        let full = compiled.query_full(&query);
+        assert_eq!(compiled.query_beam(&query, &Beam::default()), full);
+
+        let wide = Beam { width: Some(40), delta: Some(20.0) };
+        assert_eq!(hmm.compile().with_beam(wide).query(&query), full);
+
+        // Too narrow to find the best path, but it should still be a complete one
+        let narrow_beam = Beam { width: Some(8), delta: Some(10.0) };
+        let narrow = hmm.compile().with_beam(narrow_beam);
+        let (path, query_indexes) = narrow.query(&query);
+        assert_eq!(path.last(), Some(&"test_end"));
+        assert_eq!(query_indexes.last(), Some(&(query.len())));
+
+        // Its traceback doesn't fit in the memory budget, so it's decoded exactly instead
+        let limited = hmm.compile().with_beam(narrow_beam).with_viterbi_memory_budget(0);
+        assert_eq!(limited.query(&query), full);
+    }
+
+    #[test]
+    fn ambiguity_code_emissions() {
+        let settings = HMMBuildSettings::default();
+        let hmm = create_pHMM(&sequence_to_bytes("ACGT"), &settings, Some("test"));
+        let compiled = hmm.compile();
+        assert_eq!(try_sequence_to_bytes("ACGU"), Err(SequenceError::InvalidCharacter { character: 'U', position: 3 }));
+
+        // R is A or G, so the path emits it with the sum of their probabilities
+        let query = try_sequence_to_bytes("acRt").unwrap();
+        let (path, query_indexes) = compiled.query(&query);
+        assert_eq!(path, ["test_start", "test_M0", "test_M1", "test_M2", "test_M3", "test_end"]);
+        let score = |q: &str| *compiled.path_scores(&sequence_to_bytes(q), &path, &query_indexes).last().unwrap();
+        let expected = log_sum_exp([score("ACAT"), score("ACGT")].into_iter());
+        assert!((compiled.path_scores(&query, &path, &query_indexes).last().unwrap() - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn quality_mixed_emissions() {
+        let settings = HMMBuildSettings::default();
+        let hmm = create_pHMM(&sequence_to_bytes("ACGTACGT"), &settings, Some("test"));
+        let compiled = hmm.compile();
+        let query = sequence_to_bytes("ACGTTCGT");
+        let (path, query_indexes) = compiled.query(&query);
+        let score = |qualities: &[u8]| {
+            let query = QualityQuery::from_phred(query.clone(), qualities).unwrap();
+            assert_eq!(compiled.query(&query), (path.clone(), query_indexes.clone()));
+            *compiled.path_scores(&query, &path, &query_indexes).last().unwrap()
+        };
+        let plain = *compiled.path_scores(&query, &path, &query_indexes).last().unwrap();
+        assert!((score(b"~~~~~~~~") - plain).abs() < 1e-6);
+        // The mismatch costs less when it's a low quality base
+        assert!(score(b"~~~~#~~~") > plain);
+        assert!(QualityQuery::from_phred(query.clone(), b"~~~").is_err());
+    }
+
+    #[test]
+    fn vectorised_matches_scalar() {
+        let settings = HMMBuildSettings::default().with_alignment_mode(AlignmentMode::Local);
+        let motifs = ["ACGTGCGAT", "GTAACGAG", "GAAGCTACT"];
+        let hmm = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2", "Rep3"], &settings, "test");
+        let vectorised = hmm.compile();
+        assert!(!vectorised.phmm_lanes.is_empty());
+        let scalar = hmm.compile().with_vectorised(false);
+        for seq in [
+            format!("{}{}{}{}", motifs[0], motifs[0], "ATGATCGATTTGTAAACTACT", motifs[1]),
+            format!("{}NNRY{}", &motifs[2][3..], &motifs[1][..5]),
+            "T".to_string(),
+        ] {
+            let query = sequence_to_bytes(&seq);
+            let (path, query_indexes) = scalar.query_full(&query);
+            assert_eq!(vectorised.query_full(&query), (path.clone(), query_indexes.clone()), "{seq}");
+            assert_eq!(vectorised.query_checkpointed(&query), (path.clone(), query_indexes.clone()), "{seq}");
+
+            // Every score and traceback cell is the same, not just the path
+            let n_states = scalar.n_states();
+            let columns = [&scalar, &vectorised].map(|m| {
+                let mut prev = vec![f64::NEG_INFINITY; n_states];
+                let mut cur = vec![f64::NEG_INFINITY; n_states];
+                let mut trace = vec![NO_STATE; n_states];
+                (0..=query.len()).map(|col| {
+                    std::mem::swap(&mut prev, &mut cur);
+                    m.viterbi_column(&query, col, &prev, &mut cur, &mut trace);
+                    (cur.iter().map(|s| s.to_bits()).collect::<Vec<_>>(), trace.clone())
+                }).collect::<Vec<_>>()
+            });
+            assert!(columns[0] == columns[1], "{seq}");
+        }
+    }
+
+    #[test]
+    fn interval_alignments() {
+        let summary = AlignmentSummary::from_ops("==X=IDD".chars());
+        assert_eq!((summary.cigar.as_str(), summary.mismatches, summary.insertions, summary.deletions), ("2=1X1=1I2D", 1, 1, 2));
+        assert_eq!(summary.identity(), 300.0 / 7.0);
+
+        let settings = HMMBuildSettings::default();
+        let hmm = create_HMM_from_motifs(&["ACGTGCGAT", "GTAACGAG"], &["Rep1", "Rep2"], &settings, "test").compile();
+        // Exact copy, a mismatch, a deletion and an insertion
+        let query = sequence_to_bytes(&["ACGTGCGAT", "ACGTACGAT", "GTAAGAG", "ACGTGTCGAT"].concat());
+        let (path, query_indexes) = hmm.query(&query);
+        let mut intervals = hmm.convert_to_intervals(path.clone(), query_indexes.clone());
+        hmm.align_intervals(&mut intervals, &query, &path, &query_indexes);
+        let cigars = intervals.iter()
+            .map(|(n, i)| (*n, i.alignment.as_ref().map(|a| a.cigar.as_str())))
+            .collect::<Vec<_>>();
+        assert_eq!(cigars, [
+            ("test", None),
+            ("Rep1", Some("9=")),
+            ("Rep1", Some("4=1X4=")),
+            ("Rep2", Some("4=1D3=")),
+            ("Rep1", Some("5=1I4=")),
+        ]);
+        let targets = intervals.iter().map(|(_, i)| i.alignment.as_ref().and_then(|a| a.target)).collect::<Vec<_>>();
+        assert_eq!(targets, [None, Some((0, 9)), Some((0, 9)), Some((0, 8)), Some((0, 9))]);
+    }
+    #[test]
+    fn truncated_path_confidence() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let hmm = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2"], &settings, "test").compile();
+        // The query stops partway through the second copy, and so does the path: cut after the
+        // last base so the copy and the loop around it are never closed
+        let query = sequence_to_bytes(&format!("{}{}", motifs[0], &motifs[1][..5]));
+        let posteriors = hmm.posteriors(&query);
+        let (mut path, mut query_indexes) = hmm.query_posterior_with(&posteriors);
+        let last_base = (0..path.len()).rev()
+            .find(|step| hmm.annotation(hmm.state_index(path[*step]).unwrap()).role == StateRole::Match)
+            .unwrap();
+        path.truncate(last_base + 1);
+        query_indexes.truncate(last_base + 1);
+
+        let intervals = hmm.convert_to_intervals_with_confidence(path, query_indexes, &posteriors);
+        let unclosed = intervals.iter().filter(|(_, i)| i.stop == usize::MAX).map(|(n, _)| *n).collect::<Vec<_>>();
+        assert_eq!(unclosed, ["test", "Rep2"]);
+        for (name, interval) in intervals.iter() {
+            let confidence = interval.confidence.unwrap();
+            assert!((0.5..=1.0 + 1e-9).contains(&confidence), "{name}: {confidence}");
+        }
+    }
+}
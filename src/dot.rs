@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::iter::zip;
+use crate::hmm::{HMM, StateRole};
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\\\""))
+}
+
+fn format_prob(p: f64) -> String {
+    if p == 0.0 || p >= 0.001 {
+        format!("{p:.3}")
+    } else {
+        format!("{p:.1e}")
+    }
+}
+
+fn is_phmm_state(hmm: &HMM, state_i: usize) -> bool {
+    let annotation = hmm.states[state_i].annotation();
+    annotation.motif.is_some() && matches!(annotation.role, StateRole::Match | StateRole::Insert | StateRole::Delete)
+}
+
+/// Node each pHMM match, insert and delete state is collapsed into, along with the node's motif
+/// and length. A pHMM is the states of a motif joined by transitions between them, so unrolled
+/// copies of a motif get a node each, the later ones numbered
+fn phmm_nodes(hmm: &HMM) -> HashMap<&str, (String, &str, usize)> {
+    let index_map = hmm.get_index_map();
+    let motif = |state_i: usize| hmm.states[state_i].annotation().motif.as_deref();
+    let mut component = (0..hmm.states.len()).collect::<Vec<_>>();
+    fn find(component: &mut [usize], mut i: usize) -> usize {
+        while component[i] != i {
+            component[i] = component[component[i]];
+            i = component[i];
+        }
+        i
+    }
+    for (state_i, state) in hmm.states.iter().enumerate() {
+        if !is_phmm_state(hmm, state_i) {
+            continue;
+        }
+        for prev_state in state.prev_states() {
+            let prev_i = index_map[prev_state];
+            if is_phmm_state(hmm, prev_i) && motif(prev_i) == motif(state_i) {
+                let (a, b) = (find(&mut component, prev_i), find(&mut component, state_i));
+                component[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut names: HashMap<usize, String> = HashMap::new();
+    let mut copies: HashMap<&str, usize> = HashMap::new();
+    let mut positions: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut nodes = HashMap::new();
+    for (state_i, state) in hmm.states.iter().enumerate() {
+        if !is_phmm_state(hmm, state_i) {
+            continue;
+        }
+        let root = find(&mut component, state_i);
+        let motif = motif(state_i).unwrap();
+        let name = names.entry(root).or_insert_with(|| {
+            let copy = copies.entry(motif).or_insert(0);
+            *copy += 1;
+            match *copy {
+                1 => format!("{motif}_pHMM"),
+                copy => format!("{motif}_pHMM{copy}"),
+            }
+        }).clone();
+        if let Some(position) = state.annotation().position {
+            let range = positions.entry(root).or_insert((position, position));
+            *range = (range.0.min(position), range.1.max(position));
+        }
+        nodes.insert(state.identifier.as_str(), (name, motif, root));
+    }
+    nodes.into_iter()
+        .map(|(state, (name, motif, root))| {
+            let length = positions.get(&root).map_or(0, |(first, last)| last - first + 1);
+            (state, (name, motif, length))
+        })
+        .collect()
+}
+
+/// Writes the model topology as a Graphviz DOT digraph, with an edge for every transition
+/// labelled with its probability. Silent states are boxes.
+/// With `collapse_phmms`, the match, insert and delete states of each pHMM are drawn as a single
+/// node labelled with its length. Transitions inside a pHMM are then left out. Edges into a pHMM
+/// from one state are labelled with their total probability. Other merged edges are labelled
+/// with how many transitions they stand for.
+/// * `writer`:
+/// * `collapse_phmms`:
+pub fn write_dot<W: Write>(hmm: &HMM, writer: &mut W, collapse_phmms: bool) -> io::Result<()> {
+    let phmms = match collapse_phmms {
+        true => phmm_nodes(hmm),
+        false => HashMap::new(),
+    };
+    let node = |name: &str| match phmms.get(name) {
+        Some((node, _, _)) => node.clone(),
+        None => name.to_string(),
+    };
+
+    writeln!(writer, "digraph HMM {{")?;
+    writeln!(writer, "    rankdir=LR;")?;
+    let mut phmm_labels = BTreeMap::new();
+    for state in hmm.states.iter() {
+        match phmms.get(state.identifier.as_str()) {
+            Some((node, motif, length)) => {
+                phmm_labels.insert(node.clone(), format!("{motif}\\npHMM ({length})"));
+            }
+            None => {
+                let shape = if state.emits() { "ellipse" } else { "box" };
+                writeln!(writer, "    {} [shape={shape}];", quote(&state.identifier))?;
+            }
+        }
+    }
+    for (node, label) in phmm_labels.iter() {
+        writeln!(writer, "    {} [shape=box3d, label={}];", quote(node), quote(label))?;
+    }
+
+    // Edges between the same two nodes, in the order they first appear
+    let mut edges = Vec::new();
+    let mut edge_transitions: Vec<Vec<(&str, f64)>> = Vec::new();
+    let mut edge_index = HashMap::new();
+    for state in hmm.states.iter() {
+        let to = node(&state.identifier);
+        for (prev_state, trans_lp) in zip(state.prev_states(), state.prev_state_transitions()) {
+            let from = node(prev_state);
+            if from == to && phmms.contains_key(prev_state.as_str()) {
+                continue;
+            }
+            let i = *edge_index.entry((from.clone(), to.clone())).or_insert_with(|| {
+                edges.push((from, to.clone()));
+                edge_transitions.push(Vec::new());
+                edges.len() - 1
+            });
+            edge_transitions[i].push((prev_state.as_str(), trans_lp.exp()));
+        }
+    }
+    for ((from, to), transitions) in zip(edges, edge_transitions) {
+        let label = match transitions.as_slice() {
+            [(_, p)] => format_prob(*p),
+            [(first, _), rest @ ..] if rest.iter().all(|(s, _)| s == first) => {
+                format_prob(transitions.iter().map(|(_, p)| p).sum())
+            }
+            _ => format!("{} transitions", transitions.len()),
+        };
+        writeln!(writer, "    {} -> {} [label={}];", quote(&from), quote(&to), quote(&label))?;
+    }
+    writeln!(writer, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::*;
+    use crate::utils::sequence_to_bytes;
+
+    #[test]
+    fn dot_export() {
+        let settings = HMMBuildSettings::default();
+        let hmm = create_pHMM(&sequence_to_bytes("ACGT"), &settings, Some("test"));
+        let mut full = Vec::new();
+        write_dot(&hmm, &mut full, false).unwrap();
+        let full = String::from_utf8(full).unwrap();
+        let transitions = hmm.states.iter().map(|s| s.prev_states().len()).sum::<usize>();
+        assert_eq!(full.matches(" -> ").count(), transitions);
+        assert!(full.contains("\"test_M0\" -> \"test_M1\" [label=\"0.999\"];"), "{full}");
+
+        let mut collapsed = Vec::new();
+        write_dot(&hmm, &mut collapsed, true).unwrap();
+        let collapsed = String::from_utf8(collapsed).unwrap();
+        assert!(collapsed.contains("\"test_pHMM\" [shape=box3d, label=\"test\\npHMM (4)\"];"), "{collapsed}");
+        assert!(collapsed.contains("\"test_start\" -> \"test_pHMM\" [label=\"1.000\"];"), "{collapsed}");
+        assert_eq!(collapsed.matches(" -> ").count(), 2);
+
+        // Unrolled copies of a motif are separate pHMMs
+        let copies = create_HMM_from_motifs_with_copies(&["ACG"], &["Rep1"], &settings, "loop", CopyRange::new(2, Some(2)).unwrap());
+        let mut collapsed = Vec::new();
+        write_dot(&copies, &mut collapsed, true).unwrap();
+        let collapsed = String::from_utf8(collapsed).unwrap();
+        assert!(collapsed.contains("\"Rep1_pHMM\" [shape=box3d, label=\"Rep1\\npHMM (3)\"];"), "{collapsed}");
+        assert!(collapsed.contains("\"copy2:Rep1_start\" -> \"Rep1_pHMM2\""), "{collapsed}");
+        assert!(!collapsed.contains("_M0"), "{collapsed}");
+    }
+}
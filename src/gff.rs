@@ -0,0 +1,142 @@
+use std::io::{self, Write};
+use crate::utils::{IntervalNode, IntervalTree, RecordScores};
+
+const SOURCE: &str = "sva_typer";
+
+/// Percent-encodes the characters GFF3 reserves in attribute values
+fn escape(value: &str) -> String {
+    value.chars().map(|c| match c {
+        ';' | '=' | '&' | ',' | '%' => format!("%{:02X}", c as u32),
+        c if c.is_control() => format!("%{:02X}", c as u32),
+        c => c.to_string(),
+    }).collect()
+}
+
+fn score_column(score: Option<f64>) -> String {
+    score.map_or(".".to_string(), |s| format!("{s:.2}"))
+}
+
+/// Sequence ontology type of an interval: regions made of other intervals, the copies of a motif
+/// (or slices of a consensus), and anything else
+fn feature_type(node: &IntervalNode) -> &'static str {
+    match (node.children.is_empty(), node.interval.alignment.as_ref().and_then(|a| a.target)) {
+        (false, _) => "repeat_region",
+        (true, Some(_)) => "repeat_unit",
+        (true, None) => "region",
+    }
+}
+
+/// * `writer`:
+pub fn write_gff_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "##gff-version 3")
+}
+
+/// Writes the typed element as a `mobile_genetic_element` feature spanning all its intervals, with
+/// a feature for each non-empty interval linked to the element or the region it's inside of
+/// through `Parent`. IDs are the record ID (plus the path rank with more than one path) followed by
+/// the interval IDs. The score is the bit score of the interval (of the whole path for the
+/// element) when there is one. Motif copies have their motif coordinates as `Target`. The 0-based
+/// half-open intervals become 1-based inclusive coordinates by adding 1 to the starts
+/// * `writer`:
+/// * `seqname`:
+/// * `intervals`:
+/// * `scores`:
+pub fn write_gff3<W: Write>(writer: &mut W, seqname: &str, intervals: &IntervalTree, scores: &RecordScores) -> io::Result<()> {
+    let features = intervals.nodes.iter()
+        .enumerate()
+        .filter(|(_, n)| n.interval.stop > n.interval.start)
+        .collect::<Vec<_>>();
+    let (Some(start), Some(stop)) = (
+        features.iter().map(|(_, n)| n.interval.start).min(),
+        features.iter().map(|(_, n)| n.interval.stop).max(),
+    ) else {
+        return Ok(());
+    };
+    let strand = scores.strand.unwrap_or('.');
+    let element_id = match scores.path_rank {
+        Some(rank) => escape(&format!("{seqname}.path{rank}")),
+        None => escape(seqname),
+    };
+    writeln!(
+        writer, "{seqname}\t{SOURCE}\tmobile_genetic_element\t{}\t{stop}\t{}\t{strand}\t.\tID={element_id}",
+        start + 1, score_column(scores.viterbi_bits)
+    )?;
+
+    for (id, node) in features {
+        let parent = node.parent.map_or(element_id.clone(), |p| format!("{element_id}.{p}"));
+        let mut attributes = format!("ID={element_id}.{id};Name={};Parent={parent}", escape(node.name));
+        if let Some((target_start, target_stop)) = node.interval.alignment.as_ref().and_then(|a| a.target) {
+            attributes.push_str(&format!(";Target={} {} {target_stop}", escape(node.name), target_start + 1));
+        }
+        writeln!(
+            writer, "{seqname}\t{SOURCE}\t{}\t{}\t{}\t{}\t{strand}\t.\t{attributes}",
+            feature_type(node), node.interval.start + 1, node.interval.stop, score_column(node.interval.bits)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{HMMBuildSettings, create_HMM_from_motifs};
+    use crate::utils::{AlignmentSummary, Interval, sequence_to_bytes};
+
+    #[test]
+    fn gff_export() {
+        assert_eq!(escape("a;b=c,d"), "a%3Bb%3Dc%2Cd");
+
+        let interval = |start, stop, target: Option<(usize, usize)>| Interval {
+            start,
+            stop,
+            confidence: None,
+            bits: Some(1.5),
+            alignment: target.map(|target| AlignmentSummary { target: Some(target), ..Default::default() }),
+        };
+        let intervals = vec![
+            ("skip", interval(0, 0, None)),
+            ("region", interval(0, 12, None)),
+            ("hex", interval(0, 6, Some((0, 6)))),
+            ("hex", interval(6, 12, Some((1, 6)))),
+            ("SINE", interval(12, 20, Some((100, 108)))),
+        ];
+        let tree = IntervalTree::new(intervals, &[None, None, Some(1), Some(1), None]);
+        let scores = RecordScores { viterbi_bits: Some(10.0), path_rank: Some(1), ..Default::default() };
+
+        let mut gff = Vec::new();
+        write_gff3(&mut gff, "read1", &tree, &scores).unwrap();
+        let gff = String::from_utf8(gff).unwrap();
+        let lines = gff.lines().collect::<Vec<_>>();
+        assert_eq!(lines, [
+            "read1\tsva_typer\tmobile_genetic_element\t1\t20\t10.00\t.\t.\tID=read1.path1",
+            "read1\tsva_typer\trepeat_region\t1\t12\t1.50\t.\t.\tID=read1.path1.1;Name=region;Parent=read1.path1",
+            "read1\tsva_typer\trepeat_unit\t1\t6\t1.50\t.\t.\tID=read1.path1.2;Name=hex;Parent=read1.path1.1;Target=hex 1 6",
+            "read1\tsva_typer\trepeat_unit\t7\t12\t1.50\t.\t.\tID=read1.path1.3;Name=hex;Parent=read1.path1.1;Target=hex 2 6",
+            "read1\tsva_typer\trepeat_unit\t13\t20\t1.50\t.\t.\tID=read1.path1.4;Name=SINE;Parent=read1.path1;Target=SINE 101 108",
+        ]);
+    }
+    #[test]
+    fn gff_decoded_coordinates() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let hmm = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2"], &settings, "test").compile();
+        let query = sequence_to_bytes(&[motifs[0], motifs[1], motifs[0]].concat());
+        let (path, query_indexes) = hmm.query(&query);
+        let mut intervals = hmm.convert_to_intervals(path.clone(), query_indexes.clone());
+        hmm.align_intervals(&mut intervals, &query, &path, &query_indexes);
+        let tree = IntervalTree::new(intervals, &hmm.interval_parents(&path));
+
+        let mut gff = Vec::new();
+        write_gff3(&mut gff, "read1", &tree, &RecordScores::default()).unwrap();
+        let features = String::from_utf8(gff).unwrap()
+            .lines()
+            .map(|l| {
+                let columns = l.split('\t').collect::<Vec<_>>();
+                let target = columns[8].split(';').find_map(|a| a.strip_prefix("Target=")).unwrap_or("");
+                format!("{} {} {target}", columns[3], columns[4])
+            })
+            .collect::<Vec<_>>();
+        // 1-based inclusive, so each copy starts right after the one before ends
+        assert_eq!(features, ["1 26 ", "1 26 ", "1 9 Rep1 1 9", "10 17 Rep2 1 8", "18 26 Rep1 1 9"]);
+    }
+}
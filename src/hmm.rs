@@ -1,19 +1,129 @@
 use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
 use std::iter::zip;
-use crate::utils::Interval;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::utils::{Interval, IntervalTree, QuerySeq};
+use crate::compiled::{CompiledHmm, Posteriors};
+use rand::{SeedableRng, rngs::StdRng};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum HmmValidationError {
+    #[error("There should be exactly one start state, found {n}: {0:?}", n = .0.len())]
+    StartStates(Vec<String>),
+    #[error("There should be exactly one end state, found {n}: {0:?}", n = .0.len())]
+    EndStates(Vec<String>),
+    #[error("State identifier {0} is used by {1} states")]
+    DuplicateIdentifier(String, usize),
+    #[error("State {state} has {prev_states} previous states but {transitions} previous state transitions")]
+    TransitionCountMismatch {
+        state: String,
+        prev_states: usize,
+        transitions: usize,
+    },
+    #[error("State {state} has a previous state {prev_state} that does not exist")]
+    MissingPrevState {
+        state: String,
+        prev_state: String,
+    },
+    #[error("Emission probabilities for state {0} sum to {1}, not 1")]
+    EmissionSum(String, f64),
+    #[error("Transition probabilities for state {0} sum to {1}, not 1")]
+    TransitionSum(String, f64),
+    #[error("Silent states can't be ordered because of a cycle among (or before) {0:?}")]
+    SilentCycle(Vec<String>),
+}
+
+/// Identifies files written by `HMM::write_model`
+const MODEL_MAGIC: &[u8; 8] = b"SVAHMM\0\0";
+/// Bumped whenever the serialized layout of `HMM` changes, so old model files are rejected
+/// instead of being misread
+pub const MODEL_FORMAT_VERSION: u32 = 2;
+
+#[derive(Error, Debug)]
+pub enum ModelFileError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not a model file")]
+    NotAModel,
+    #[error("Model file has format version {0}, but this version reads version {MODEL_FORMAT_VERSION}")]
+    Version(u32),
+    #[error("Could not decode the model: {0}")]
+    Decode(#[from] bincode::Error),
+}
+
+/// Every problem found by `HMM::validate`
+#[derive(Error, Debug)]
+#[error("Invalid HMM ({n} problems):\n{list}", n = .0.len(), list = .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"))]
+pub struct HmmValidationReport(pub Vec<HmmValidationError>);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum HmmEmission {
     NoEmit,
     Emission(Vec<f64>),
 }
 
-#[derive(Clone, Debug)]
+/// What a state does in the structure the builders put together
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StateRole {
+    /// Silent state opening the region's interval
+    Start,
+    /// Silent state closing the region's interval
+    End,
+    /// Silent state each copy of a loop starts from, which isn't an interval of its own
+    LoopStart,
+    /// Silent state each copy of a loop ends in
+    LoopEnd,
+    /// Emitting state of a skip region
+    Skip,
+    Match,
+    Insert,
+    Delete,
+    #[default]
+    Other,
+}
+
+/// Structural metadata of a state, filled in by the builders and the .hmm reader. Intervals and
+/// training read this rather than the state identifiers
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StateAnnotation {
+    /// Name of the interval the state is part of, e.g. `hex` for a hexamer pHMM or
+    /// `hexamer_region` for the loop around it
+    pub region: String,
+    /// Repeat unit or consensus a pHMM state models
+    pub motif: Option<String>,
+    pub role: StateRole,
+    /// Position in the motif or consensus of match, insert and delete states
+    pub position: Option<usize>,
+}
+
+impl StateAnnotation {
+    pub fn new(region: &str, role: StateRole) -> Self {
+        StateAnnotation { region: region.to_string(), motif: None, role, position: None }
+    }
+
+    /// State of a pHMM, whose interval is named after its motif
+    /// * `motif`:
+    /// * `role`:
+    /// * `position`: for match, insert and delete states
+    pub fn phmm(motif: &str, role: StateRole, position: Option<usize>) -> Self {
+        StateAnnotation { region: motif.to_string(), motif: Some(motif.to_string()), role, position }
+    }
+
+    /// Whether the state starts an interval. Unnamed regions, like a pHMM built without a prefix,
+    /// don't get one
+    pub fn starts_interval(&self) -> bool {
+        self.role == StateRole::Start && !self.region.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct HMMState {
     pub identifier: String,
     emission: HmmEmission,
     prev_states: Vec<String>,
     prev_state_transitions: Vec<f64>,
+    annotation: StateAnnotation,
 }
 
 impl HMMState {
@@ -52,6 +162,7 @@ impl HMMState {
             },
             prev_states,
             prev_state_transitions,
+            annotation: StateAnnotation::default(),
         }
 
     }
@@ -61,16 +172,49 @@ impl HMMState {
             emission: HmmEmission::NoEmit,
             prev_states: Vec::new(),
             prev_state_transitions: Vec::new(),
+            annotation: StateAnnotation::default(),
         }
     }
 
+    pub fn with_annotation(mut self, annotation: StateAnnotation) -> Self {
+        self.annotation = annotation;
+        self
+    }
+
+    pub fn emission(&self) -> Option<&[f64]> {
+        match &self.emission {
+            HmmEmission::NoEmit => None,
+            HmmEmission::Emission(v) => Some(v),
+        }
+    }
+
+    pub fn prev_states(&self) -> &[String] {
+        &self.prev_states
+    }
+
+    pub fn prev_state_transitions(&self) -> &[f64] {
+        &self.prev_state_transitions
+    }
+
+    pub fn annotation(&self) -> &StateAnnotation {
+        &self.annotation
+    }
+
+    pub fn set_annotation(&mut self, annotation: StateAnnotation) {
+        self.annotation = annotation;
+    }
+
+    pub fn emits(&self) -> bool {
+        matches!(self.emission, HmmEmission::Emission(_))
+    }
+
     pub fn set_transitions(&mut self, prev_states: Vec<String>, prev_state_transitions: Vec<f64>) {
         self.prev_states = prev_states;
         self.prev_state_transitions = prev_state_transitions.iter().map(|p| p.ln()).collect();
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct HMM {
     pub states: Vec<HMMState>,
 }
@@ -92,51 +236,70 @@ impl HMM {
         map
     }
 
-    // TODO: Change to result output
+    /// Panics with every problem found by `validate`
     pub fn check_valid(&self) {
-        // Check that there is only one start
+        if let Err(report) = self.validate() {
+            panic!("{report}");
+        }
+    }
+
+    /// Checks the structure and probabilities of the model, collecting every problem found
+    /// instead of stopping at the first one
+    pub fn validate(&self) -> Result<(), HmmValidationReport> {
+        let mut errors = Vec::new();
+
         let start_states = self.get_start_states();
         if start_states.len() != 1 {
-            panic!(
-                "There should be exactly one start state, found {}",
-                start_states.len()
-            );
+            errors.push(HmmValidationError::StartStates(start_states.iter().map(|s| s.to_string()).collect()));
         }
         let end_states = self.get_end_states();
         if end_states.len() != 1 {
-            panic!(
-                "There should be exactly one end state, found {}",
-                end_states.len()
-            );
+            errors.push(HmmValidationError::EndStates(end_states.iter().map(|s| s.to_string()).collect()));
         }
 
-        // First check that all states are constructed correctly
+        let mut identifier_counts: HashMap<&str, usize> = HashMap::new();
         for state in self.states.iter() {
-            if state.prev_states.len() != state.prev_state_transitions.len() {
-                panic!("State {} does not have the same number of previous states as previous state transitions:\n{:?}", state.identifier, state);
-            }
+            *identifier_counts.entry(state.identifier.as_str()).or_default() += 1;
         }
-        let all_identifiers: Vec<String> =
-            self.states.iter().map(|s| s.identifier.clone()).collect();
-        let all_identifier_set: HashSet<String> = all_identifiers.iter().cloned().collect();
-
-        if all_identifiers.len() != all_identifier_set.len() {
-            panic!("State identifiers are not unique");
+        for state in self.states.iter() {
+            match identifier_counts.get_mut(state.identifier.as_str()) {
+                Some(count) if *count > 1 => {
+                    errors.push(HmmValidationError::DuplicateIdentifier(state.identifier.clone(), *count));
+                    // Only report each duplicate once
+                    *count = 1;
+                }
+                _ => {}
+            }
         }
 
-        self.states.iter().for_each(|state| {
+        for state in self.states.iter() {
+            if state.prev_states.len() != state.prev_state_transitions.len() {
+                errors.push(HmmValidationError::TransitionCountMismatch {
+                    state: state.identifier.clone(),
+                    prev_states: state.prev_states.len(),
+                    transitions: state.prev_state_transitions.len(),
+                });
+            }
             for prev_state in state.prev_states.iter() {
-                if !all_identifier_set.contains(prev_state) {
-                    panic!(
-                        "State {} has a previous state {} that does not exist",
-                        state.identifier, prev_state
-                    );
+                if !identifier_counts.contains_key(prev_state.as_str()) {
+                    errors.push(HmmValidationError::MissingPrevState {
+                        state: state.identifier.clone(),
+                        prev_state: prev_state.clone(),
+                    });
                 }
             }
-        });
+        }
+
+        errors.extend(self.emission_errors());
+        errors.extend(self.transition_errors());
+        if let Err(e) = self.silent_order() {
+            errors.push(e);
+        }
 
-        self.check_valid_emissions();
-        self.check_valid_transitions();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(HmmValidationReport(errors)),
+        }
     }
 
     /// Properly orders the nonemitting states of the HMM so that their viterbi scores are
@@ -144,191 +307,213 @@ impl HMM {
     ///
     /// Make sure this is called after creating an HMM or the probabilities might get messed up
     pub fn order_states(&mut self) {
+        if let Err(e) = self.try_order_states() {
+            panic!("{e}");
+        }
+    }
+
+    /// Same as `order_states`, but returns an error instead of panicking if the silent states
+    /// can't be ordered
+    pub fn try_order_states(&mut self) -> Result<(), HmmValidationError> {
         let pos_map = self.get_index_map();
-        let copy = self.states.clone();
-        let mut normal_states: Vec<String> = Vec::new();
-        let mut silent_states: Vec<String> = Vec::new();
+        let mut normal_states: Vec<String> = self.states.iter()
+            .filter(|s| s.emits())
+            .map(|s| s.identifier.clone())
+            .collect();
+        normal_states.extend(self.silent_order()?);
 
-        for state in self.states.iter() {
-            match state.emission {
-                HmmEmission::NoEmit => silent_states.push(state.identifier.clone()),
-                HmmEmission::Emission(_) => normal_states.push(state.identifier.clone()),
-            }
+        let copy = std::mem::take(&mut self.states);
+        for state in normal_states.iter() {
+            self.states.push(copy[pos_map[state]].clone())
         }
+        Ok(())
+    }
 
-        //
-        let mut sorted = Vec::new();
+    /// Silent states in an order where every silent state comes after its silent previous states
+    fn silent_order(&self) -> Result<Vec<String>, HmmValidationError> {
+        let mut silent_states: Vec<&HMMState> = self.states.iter().filter(|s| !s.emits()).collect();
 
+        let mut sorted = Vec::new();
         while !silent_states.is_empty() {
+            let remaining: HashSet<&str> = silent_states.iter().map(|s| s.identifier.as_str()).collect();
             let mut unused = Vec::new();
 
-            for state in &silent_states {
-                let has_incoming_silent = self.states[pos_map[state]]
-                    .prev_states
+            for state in silent_states.iter() {
+                let has_incoming_silent = state.prev_states
                     .iter()
-                    .any(|s| silent_states.contains(s));
+                    .any(|s| remaining.contains(s.as_str()));
                 if !has_incoming_silent {
-                    sorted.push(state.to_string())
+                    sorted.push(state.identifier.clone())
                 } else {
-                    unused.push(state.to_string());
+                    unused.push(*state);
                 }
             }
 
-            assert!(unused.len() < silent_states.len());
+            if unused.len() == silent_states.len() {
+                return Err(HmmValidationError::SilentCycle(
+                    unused.iter().map(|s| s.identifier.clone()).collect()
+                ));
+            }
             silent_states = unused;
         }
-        normal_states.extend(sorted);
+        Ok(sorted)
+    }
 
-        self.states.clear();
-        for state in normal_states.iter() {
-            self.states.push(copy[pos_map[state]].clone())
+    /// Writes the model in the versioned binary model format. The states are stored in their
+    /// current order, so write models that are ready to query
+    /// * `writer`:
+    pub fn write_model<W: Write>(&self, mut writer: W) -> Result<(), ModelFileError> {
+        writer.write_all(MODEL_MAGIC)?;
+        writer.write_all(&MODEL_FORMAT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads a model written by `write_model`, exactly as it was written
+    /// * `reader`:
+    pub fn read_model<R: Read>(mut reader: R) -> Result<Self, ModelFileError> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic).map_err(|_| ModelFileError::NotAModel)?;
+        if &magic != MODEL_MAGIC {
+            return Err(ModelFileError::NotAModel);
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != MODEL_FORMAT_VERSION {
+            return Err(ModelFileError::Version(version));
         }
+        Ok(bincode::deserialize_from(reader)?)
     }
 
-    /// 
-    /// States MUST be in order using self.order_states() beforehand. Otherwise the log
-    /// probabilities for the nonemitting states will get messed up. This is so self can be passed
-    /// as a immutable reference making it easier for parallelization
-    /// * `query`: 
-    pub fn query(&self, query: &[u8]) -> (Vec<&str>, Vec<usize>) {
-    // pub fn query(&mut self, query: &[u8]) -> (Vec<&str>, Vec<usize>) {
-        let start_state = self.get_start_states()[0];
-        let end_state = self.get_end_states()[0];
-        let (_lp_mat, trace_mat) = self.gen_viterbi_mats(query, start_state);
-        // panic!("Trace mat: {:?}", _lp_mat);
-        let index_map = self.get_index_map();
-        let (path, query_indexes) = self.traceback(&trace_mat, &index_map, start_state, end_state);
+    /// Compiles the HMM into the integer-indexed representation used for querying. States MUST be
+    /// in order using self.order_states() beforehand
+    pub fn compile(&self) -> CompiledHmm {
+        CompiledHmm::new(self)
+    }
 
+    /// Viterbi path through the model. This compiles the model on every call, so use `compile`
+    /// once when querying many sequences
+    /// * `query`: query codes, or a `QualityQuery` to down-weight low quality bases
+    pub fn query<Q: QuerySeq + ?Sized>(&self, query: &Q) -> (Vec<&str>, Vec<usize>) {
+        let compiled = self.compile();
+        let (path, query_indexes) = compiled.query(query);
+        // The compiled states are in the same order, so the identifiers can be borrowed from self
+        let path = path.into_iter()
+            .map(|s| self.states[compiled.state_index(s).unwrap()].identifier.as_str())
+            .collect();
         (path, query_indexes)
+    }
 
+    /// The `k` most likely distinct state paths, best first, as the log probability of each path
+    /// and the intervals it goes through. Compiles the model on every call like `query`. See
+    /// `CompiledHmm::query_k_best`
+    /// * `query`:
+    /// * `k`:
+    pub fn query_k_best<Q: QuerySeq + ?Sized>(&self, query: &Q, k: usize) -> Vec<(f64, Vec<(&str, Interval)>)> {
+        let compiled = self.compile();
+        compiled.query_k_best(query, k)
+            .into_iter()
+            .map(|p| {
+                // The compiled states are in the same order, so the identifiers can be borrowed from self
+                let path = p.path.into_iter()
+                    .map(|s| self.states[compiled.state_index(s).unwrap()].identifier.as_str())
+                    .collect();
+                (p.score, self.convert_to_intervals(path, p.query_indexes))
+            })
+            .collect()
     }
 
-    fn gen_viterbi_mats(
-        &self,
-        query: &[u8],
-        start_state: &str,
-    ) -> (Vec<Vec<f64>>, Vec<Vec<Option<&str>>>) {
-        let mut lp_mat = vec![vec![f64::NEG_INFINITY; query.len() + 1]; self.states.len()];
-        let mut trace_mat = vec![vec![None; query.len() + 1]; self.states.len()];
-        let map = self.get_index_map();
-        lp_mat[map[start_state]][0] = 0.0;
-        for query_i in 0..(query.len() + 1) {
-            for (state_i, state) in self.states.iter().enumerate() {
-                if let Some((score, trace_state)) =
-                    self.viterbi_score(query, query_i, state, &lp_mat, &map)
-                {
-                    lp_mat[state_i][query_i] = score;
-                    trace_mat[state_i][query_i] = Some(trace_state);
-                }
-            }
-        }
-        (lp_mat, trace_mat)
-    }
-    fn viterbi_score<'a>(
-        &self,
-        query: &[u8],
-        index: usize,
-        state: &'a HMMState,
-        lp_mat: &[Vec<f64>],
-        index_map: &HashMap<String, usize>,
-    ) -> Option<(f64, &'a str)> {
-        let (ln_em, traceback): (f64, usize) = match &state.emission {
-            HmmEmission::NoEmit => (0.0, 0),
-            HmmEmission::Emission(emit_probs) => {
-                if index == 0 {
-                    // index = 0 means before first character
-                    return None;
-                }
-                let base = query[index - 1];
-                match base {
-                    4 => (0.0, 1), // 4 corresponds to N
-                    _ => (emit_probs[base as usize], 1),
-                }
-            }
-        };
-        let mut best_state = None;
-        let mut best_score = f64::NEG_INFINITY;
-
-        for (i, prev_state) in state.prev_states.iter().enumerate() {
-            let prev_state_i = index_map[prev_state];
-            let prev_score = lp_mat[prev_state_i][index - traceback];
-            let trans_lp = state.prev_state_transitions[i];
-            let score = prev_score + trans_lp + ln_em;
-            if score > best_score {
-                best_score = score;
-                best_state = Some(prev_state);
-            }
-        }
-        best_state.map(|s| (best_score, s.as_str()))
-    }
-
-    fn traceback<'a>(
-        &self,
-        trace_mat: &[Vec<Option<&'a str>>],
-        index_map: &HashMap<String, usize>,
-        start_state: &'a str,
-        end_state: &'a str
-    ) -> (Vec<&'a str>, Vec<usize>) {
-        let mut state = end_state;
-        let mut state_i = index_map[state];
-        let mut index = trace_mat[0].len() - 1;
-        let mut path = Vec::new();
-        let mut query_indexes = Vec::new();
-
-
-        while state != start_state {
-            path.push(state);
-            query_indexes.push(index);
-            let prev_state = trace_mat[state_i][index]
-                .unwrap_or_else(|| panic!("No previous state found for {}", state));
-            if let HmmEmission::Emission(_) = self.states[state_i].emission {
-                index -= 1;
-            }
-            state = prev_state;
-            state_i = index_map[state];
-        }
-        path.push(start_state);
-        query_indexes.push(index);
-        path.reverse();
-        query_indexes.reverse();
-        (path, query_indexes)
+    /// Outputs 0-based intervals for the regions (motif copies, loops, skips...) a path from this
+    /// model goes through, using the state annotations
+    /// * `state_names`:
+    /// * `state_pos`:
+    pub fn convert_to_intervals(&self, state_names: Vec<&str>, state_pos: Vec<usize>) -> Vec<(&str, Interval)> {
+        let index_map = self.get_index_map();
+        let annotations = state_names.iter()
+            .map(|s| &self.states[index_map[*s]].annotation)
+            .collect::<Vec<_>>();
+        path_intervals(&annotations, &state_pos)
     }
 
-    /// Checks if all states have valid emissions
-    fn check_valid_emissions(&self) {
-        let mut sum;
+    /// Same as `convert_to_intervals`, with the intervals arranged by how their regions nest
+    /// * `state_names`:
+    /// * `state_pos`:
+    pub fn interval_tree(&self, state_names: Vec<&str>, state_pos: Vec<usize>) -> IntervalTree<'_> {
+        let index_map = self.get_index_map();
+        let annotations = state_names.iter()
+            .map(|s| &self.states[index_map[*s]].annotation)
+            .collect::<Vec<_>>();
+        IntervalTree::new(path_intervals(&annotations, &state_pos), &interval_parents(&annotations))
+    }
+
+    /// Samples a sequence and the state path that generated it. The same seed always gives the
+    /// same sample. See `CompiledHmm::sample`
+    /// * `seed`: 
+    pub fn sample(&self, seed: u64) -> (Vec<u8>, Vec<&str>, Vec<usize>) {
+        let compiled = self.compile();
+        let (sequence, path, query_indexes) = compiled.sample(&mut StdRng::seed_from_u64(seed));
+        let path = path.into_iter()
+            .map(|s| self.states[compiled.state_index(s).unwrap()].identifier.as_str())
+            .collect();
+        (sequence, path, query_indexes)
+    }
+
+    /// Total log-likelihood of the query summed over every path through the model (forward
+    /// algorithm). Compiles the model on every call like `query`. See `CompiledHmm::forward`
+    /// * `query`: 
+    pub fn forward<Q: QuerySeq + ?Sized>(&self, query: &Q) -> f64 {
+        self.compile().forward(query)
+    }
+
+    /// Posterior probability of every state at every query column. Compiles the model on every
+    /// call like `query`. See `CompiledHmm::posteriors`
+    /// * `query`: 
+    pub fn posteriors<Q: QuerySeq + ?Sized>(&self, query: &Q) -> Posteriors {
+        self.compile().posteriors(query)
+    }
+
+    /// States whose emission probabilities don't sum to 1
+    fn emission_errors(&self) -> Vec<HmmValidationError> {
+        let mut errors = Vec::new();
         for state in self.states.iter() {
             if let HmmEmission::Emission(d) = &state.emission {
-                sum = (d.iter().map(|p| p.exp()).sum::<f64>() * 1000.0).round() / 1000.0;
+                let sum = (d.iter().map(|p| p.exp()).sum::<f64>() * 1000.0).round() / 1000.0;
                 if sum != 1.0 {
-                    panic!(
-                        "Emission probabilities for state {} sum to {}, not 1\n{:?}",
-                        state.identifier, sum, state
-                    );
+                    errors.push(HmmValidationError::EmissionSum(state.identifier.clone(), sum));
                 }
             }
         }
+        errors
     }
 
-    /// Checks if all transition probabilities sum to 1
-    fn check_valid_transitions(&self) {
-        let prob_matrix = self.get_transition_matrix();
-        let mut sum;
-        let end_state = self.get_end_states()[0];
-        for (i, row) in prob_matrix.iter().enumerate() {
-            //TODO: This is kinda lazy, change to a better check for end states
-            if end_state == self.states[i].identifier {
-                continue;
+    /// States whose outgoing transition probabilities don't sum to 1. States without any (i.e.
+    /// the end state) are skipped, and so are transitions from states that don't exist
+    fn transition_errors(&self) -> Vec<HmmValidationError> {
+        let map = self.get_index_map();
+        let mut outgoing: Vec<Option<f64>> = vec![None; self.states.len()];
+        for state in self.states.iter() {
+            for (prev_state, prob) in zip(&state.prev_states, &state.prev_state_transitions) {
+                if let Some(i) = map.get(prev_state) {
+                    *outgoing[*i].get_or_insert(0.0) += prob.exp();
+                }
             }
-            sum = (row.iter().map(|p| p.exp()).sum::<f64>() * 1000.0).round() / 1000.0;
+        }
+
+        let mut errors = Vec::new();
+        for (state, sum) in zip(&self.states, outgoing) {
+            let Some(sum) = sum else {
+                continue;
+            };
+            let sum = (sum * 1000.0).round() / 1000.0;
             if sum != 1.0 {
-                panic!(
-                    "Transition probabilities for state {} sum to {}, not 1\n{:?}",
-                    self.states[i].identifier, sum, self.states[i]
-                );
+                errors.push(HmmValidationError::TransitionSum(state.identifier.clone(), sum));
             }
         }
+        errors
     }
+
     pub fn get_start_states(&self) -> Vec<&str> {
         self.states
             .iter()
@@ -375,34 +560,77 @@ impl Default for HMM {
     }
 }
 
-/// Outputs 0-based intervals for the motif starts and stops
-/// FYI: This functional heavily assumes the naming schema for states used throughout the building process
-/// * `state_names`: 
-/// * `state_pos`: 
-pub fn convert_to_intervals(state_names: Vec<&str>, state_pos: Vec<usize>) -> Vec<(&str, Interval)> {
-    // This removes loop states by just assuming that "loop_start" and "loop_end" are in the names,
-    // which might not always be true, just fyi
-    let mut intervals = vec![];
+/// Name of a state in an unrolled copy of a loop. The first copy keeps the original names, later
+/// ones get a `copy2:` etc. prefix so every state is unique but the intervals have the same names
+pub fn copy_state_name(name: &str, copy: usize) -> String {
+    match copy {
+        0 | 1 => name.to_string(),
+        _ => format!("copy{copy}:{name}"),
+    }
+}
 
-    for (s, i) in zip(
-        state_names, 
-        state_pos.iter().map(|i| match i { 
-            // There's some funkiness because we had to add a fake start state to make the
-            // traceback work, but 0 and 1 are the same position, and everything else is minus 1
-            0 => 0,
-            _ => i-1
+/// 0-based half-open intervals of the regions the path goes through, in the order they start
+/// * `annotations`: of each state in the path
+/// * `state_pos`:
+pub(crate) fn path_intervals<'a>(annotations: &[&'a StateAnnotation], state_pos: &[usize]) -> Vec<(&'a str, Interval)> {
+    interval_steps(annotations)
+        .into_iter()
+        .map(|(name, start, stop)| {
+            let stop_col = if stop == usize::MAX { usize::MAX } else { state_pos[stop] };
+            (name, column_interval(state_pos[start], stop_col))
         })
-    )  {
-            if s.contains("_start") && !s.contains("_loop_start") {
-                intervals.push((s.strip_suffix("_start").unwrap(), Interval {start: i, stop: usize::MAX}))
-            }
-            if s.contains("_end") && !s.contains("_loop_end") {
-                for (n, interval) in intervals.iter_mut() {
-                    if n == &s.strip_suffix("_end").unwrap() && interval.stop == usize::MAX {
-                        interval.stop = i
+        .collect()
+}
+
+/// Region names along with the positions in the path of their start and end states. Loop starts
+/// and ends aren't regions of their own, so going round a loop doesn't close the loop's region
+/// * `annotations`: of each state in the path
+pub(crate) fn interval_steps<'a>(annotations: &[&'a StateAnnotation]) -> Vec<(&'a str, usize, usize)> {
+    let mut intervals = vec![];
+
+    for (step, annotation) in annotations.iter().enumerate() {
+        match annotation.role {
+            StateRole::Start if annotation.starts_interval() => intervals.push((annotation.region.as_str(), step, usize::MAX)),
+            StateRole::End => {
+                for (n, _, stop) in intervals.iter_mut() {
+                    if *n == annotation.region && *stop == usize::MAX {
+                        *stop = step
                     }
                 }
             }
+            _ => {}
+        }
     }
     intervals
 }
+
+/// Index of the interval each interval of `interval_steps` is directly inside of, if any
+/// * `annotations`: of each state in the path
+pub(crate) fn interval_parents(annotations: &[&StateAnnotation]) -> Vec<Option<usize>> {
+    let mut parents = vec![];
+    // Intervals that have started but not ended, innermost last
+    let mut open: Vec<(usize, &str)> = vec![];
+    for annotation in annotations.iter() {
+        match annotation.role {
+            StateRole::Start if annotation.starts_interval() => {
+                parents.push(open.last().map(|(i, _)| *i));
+                open.push((parents.len() - 1, &annotation.region));
+            }
+            StateRole::End => {
+                // Anything opened inside it that never ended ends too
+                if let Some(i) = open.iter().rposition(|(_, region)| *region == annotation.region) {
+                    open.truncate(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    parents
+}
+
+/// 0-based half-open interval between the query columns of a region's start and end states. A
+/// silent state in column `c` comes after the first `c` bases, so the columns are the coordinates.
+/// A `usize::MAX` stop (region never closed) is kept as it is
+pub(crate) fn column_interval(start_col: usize, stop_col: usize) -> Interval {
+    Interval { start: start_col, stop: stop_col, confidence: None, bits: None, alignment: None }
+}
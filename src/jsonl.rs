@@ -0,0 +1,119 @@
+use std::io::{self, Write};
+use serde::Serialize;
+use crate::builder::HMMBuildSettings;
+use crate::utils::{IntervalTree, RecordScores};
+
+/// Everything typed for one input record, written as a single JSON object
+#[derive(Debug, Serialize)]
+pub struct JsonRecord<'a> {
+    pub id: &'a str,
+    pub length: usize,
+    /// Built in model type, or the model file
+    pub model: &'a str,
+    /// Build settings of the model, unless it was read from a model file
+    pub settings: Option<&'a HMMBuildSettings>,
+    /// Strand that was typed, when both strands are tried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strand: Option<char>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_likelihood: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward_bits: Option<f64>,
+    /// The best path, followed by the other paths with different intervals with --k-best
+    pub paths: Vec<JsonPath<'a>>,
+    /// Why the record couldn't be typed, if it couldn't
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonPath<'a> {
+    pub rank: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viterbi_bits: Option<f64>,
+    /// Log probability of the best path minus this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score_gap: Option<f64>,
+    /// Intervals in the order they start, with the index of the interval each is inside of as
+    /// its `parent`
+    pub intervals: &'a IntervalTree<'a>,
+}
+
+impl<'a> JsonRecord<'a> {
+    /// Record without any paths yet. The per-record scores are taken from the first path added
+    /// * `id`:
+    /// * `length`:
+    /// * `model`:
+    /// * `settings`:
+    pub fn new(id: &'a str, length: usize, model: &'a str, settings: Option<&'a HMMBuildSettings>) -> Self {
+        JsonRecord {
+            id,
+            length,
+            model,
+            settings,
+            strand: None,
+            log_likelihood: None,
+            forward_bits: None,
+            paths: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// * `intervals`:
+    /// * `scores`:
+    pub fn add_path(&mut self, intervals: &'a IntervalTree<'a>, scores: &RecordScores) {
+        if self.paths.is_empty() {
+            self.strand = scores.strand;
+            self.log_likelihood = scores.log_likelihood;
+            self.forward_bits = scores.forward_bits;
+        }
+        self.paths.push(JsonPath {
+            rank: scores.path_rank.unwrap_or(0),
+            viterbi_bits: scores.viterbi_bits,
+            score_gap: scores.score_gap,
+            intervals,
+        });
+    }
+}
+
+/// Writes the record as one line of JSON
+/// * `writer`:
+/// * `record`:
+pub fn write_jsonl<W: Write>(writer: &mut W, record: &JsonRecord) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writeln!(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{AlignmentSummary, Interval};
+
+    #[test]
+    fn jsonl_export() {
+        let intervals = vec![
+            ("region", Interval { start: 0, stop: 12, confidence: Some(0.5), bits: None, alignment: None }),
+            ("hex", Interval {
+                start: 0,
+                stop: 6,
+                confidence: None,
+                bits: None,
+                alignment: Some(AlignmentSummary::from_ops("======".chars())),
+            }),
+        ];
+        let tree = IntervalTree::new(intervals, &[None, Some(0)]);
+        let settings = HMMBuildSettings::default();
+        let mut record = JsonRecord::new("read1", 20, "simple", Some(&settings));
+        record.add_path(&tree, &RecordScores { strand: Some('-'), ..Default::default() });
+
+        let mut json = Vec::new();
+        write_jsonl(&mut json, &record).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert_eq!(json.lines().count(), 1);
+        assert!(json.starts_with("{\"id\":\"read1\",\"length\":20,\"model\":\"simple\",\"settings\":{\"match_to_match\":"), "{json}");
+        assert!(json.contains("\"hexamer_copies\":{\"min\":1,\"max\":null}"), "{json}");
+        assert!(json.contains("\"strand\":\"-\",\"paths\":[{\"rank\":0,\"intervals\":["), "{json}");
+        assert!(json.contains("{\"region\":\"region\",\"start\":0,\"end\":12,\"confidence\":0.5,\"parent\":null,\"children\":[1]}"), "{json}");
+        assert!(json.contains("\"alignment\":{\"cigar\":\"6=\",\"matches\":6,\"mismatches\":0,\"insertions\":0,\"deletions\":0},\"parent\":0,\"children\":[]}"), "{json}");
+        assert!(json.ends_with("],\"warnings\":[]}\n"), "{json}");
+    }
+}
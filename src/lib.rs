@@ -1,7 +1,16 @@
 pub mod builder;
 pub mod hmm;
+pub mod compiled;
+mod simd;
 pub mod utils;
 pub mod cli;
 pub mod sva;
 pub mod reader;
+pub mod null_model;
+pub mod training;
+pub mod dot;
+pub mod bed;
+pub mod gff;
+pub mod jsonl;
+pub mod classify;
 pub mod benchmarks;
@@ -1,88 +1,429 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use sva_typer::{
     builder::HMMBuildSettings,
-    cli::{Args, SVAModelType}, 
+    cli::{Args, Command, DecodeMethod, OutputFormat, SVAModelType}, 
     utils::*,
     sva,
-    hmm
+    hmm::HMM,
+    null_model::NullModel,
+    compiled::CompiledHmm,
+    reader::read_hmm_composition,
+    training,
+    dot,
+    bed,
+    gff,
+    jsonl::{self, JsonRecord},
+    classify::{ClassifyScore, ModelRank, rank_models},
 };
-use clap::Parser;
-use bio::{self, io::fasta::{self, FastaRead}};
+use std::{fs::File, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}};
+use clap::{Parser, ValueEnum};
+use bio::io::{fasta, fastq};
 use rayon::prelude::*;
 
+struct TypedRecord<'a> {
+    path: Vec<&'a str>,
+    query_indexes: Vec<usize>,
+    intervals: IntervalTree<'a>,
+    scores: RecordScores,
+    /// Log probability of the path, only computed when something needs it
+    path_score: Option<f64>,
+}
 
-fn run(args: Args) -> Result<()> {
-    let mut reader = fasta::Reader::from_file(&args.file)?;
-    let settings = HMMBuildSettings::try_from(&args)?;
-    let hmm = match args.sva_model {
-        SVAModelType::Simple => match args.hex_motifs {
-            Some(v) => sva::gen_sva_model_with_custom_hexseq(&settings, &v),
-            None => sva::gen_sva_model(&settings)
+/// A FASTA or FASTQ record, with the qualities only kept when they're used
+struct InputRecord {
+    id: String,
+    seq: Vec<u8>,
+    qual: Option<Vec<u8>>,
+}
+
+/// Reads FASTA or FASTQ records, telling them apart by the first character of the file
+fn read_records(file: &Path, keep_qualities: bool) -> Result<Box<dyn Iterator<Item = Result<InputRecord>>>> {
+    let mut reader = BufReader::new(File::open(file)?);
+    if reader.fill_buf()?.first() == Some(&b'@') {
+        Ok(Box::new(fastq::Reader::from_bufread(reader).records().map(move |r| {
+            let r = r?;
+            Ok(InputRecord {
+                id: r.id().to_string(),
+                seq: r.seq().to_vec(),
+                qual: keep_qualities.then(|| r.qual().to_vec()),
+            })
+        })))
+    } else {
+        Ok(Box::new(fasta::Reader::from_bufread(reader).records().map(|r| {
+            let r = r?;
+            Ok(InputRecord { id: r.id().to_string(), seq: r.seq().to_vec(), qual: None })
+        })))
+    }
+}
+
+/// Decodes a single query with the method and extra outputs requested on the command line. Gives
+/// one record per decoded path, so possibly more than one with --k-best
+fn type_query<'a, Q: QuerySeq + ?Sized>(hmm: &'a CompiledHmm, query: &Q, args: &Args, null_model: &NullModel) -> Vec<TypedRecord<'a>> {
+    // Forward-backward is only needed for posterior decoding or confidences, and it gives the
+    // log-likelihood for free
+    let posteriors = match args.decode {
+        DecodeMethod::Posterior => Some(hmm.posteriors(query)),
+        DecodeMethod::Viterbi if args.write_confidence => Some(hmm.posteriors(query)),
+        DecodeMethod::Viterbi => None,
+    };
+    let paths = match &posteriors {
+        Some(p) if matches!(args.decode, DecodeMethod::Posterior) => vec![hmm.query_posterior_with(p)],
+        _ if args.k_best > 1 => hmm.query_k_best(query, args.k_best)
+            .into_iter()
+            .map(|p| (p.path, p.query_indexes))
+            .collect(),
+        _ => vec![hmm.query(query)],
+    };
+    let log_likelihood = match (&posteriors, args.write_log_likelihood || args.write_bit_score) {
+        (_, false) => None,
+        (Some(p), true) => Some(p.log_likelihood),
+        (None, true) => Some(hmm.forward(query)),
+    };
+
+    // GFF3 has a score column and the motif coordinates of each copy
+    let gff = matches!(args.format, OutputFormat::Gff3);
+    let write_bits = args.write_bit_score || gff;
+    let mut best_score = None;
+    let mut seen_intervals = Vec::new();
+    paths.into_iter().enumerate().filter_map(|(rank, (path, query_indexes))| {
+        let mut intervals = match &posteriors {
+            Some(p) if args.write_confidence => {
+                hmm.convert_to_intervals_with_confidence(path.clone(), query_indexes.clone(), p)
+            }
+            _ => hmm.convert_to_intervals(path.clone(), query_indexes.clone()),
+        };
+        if args.k_best > 1 {
+            // Many of the k best paths only differ inside a motif (e.g. where an insertion goes),
+            // so only output the ones that give new intervals
+            let key = intervals.iter().map(|(n, i)| (*n, i.start, i.stop)).collect::<Vec<_>>();
+            if seen_intervals.contains(&key) {
+                return None;
+            }
+            seen_intervals.push(key);
+        }
+        let mut scores = RecordScores {
+            log_likelihood: log_likelihood.filter(|_| args.write_log_likelihood),
+            ..Default::default()
+        };
+        let path_score = match write_bits || args.k_best > 1 || args.both_strands {
+            true => hmm.path_scores(query, &path, &query_indexes).last().copied(),
+            false => None,
+        };
+        if write_bits {
+            hmm.score_intervals(&mut intervals, query, &path, &query_indexes, null_model);
+            scores.viterbi_bits = path_score.map(|s| null_model.bits(query.codes(), s));
+            scores.forward_bits = log_likelihood.map(|s| null_model.bits(query.codes(), s));
+        }
+        if args.write_alignment || gff {
+            hmm.align_intervals(&mut intervals, query, &path, &query_indexes);
+        }
+        if args.k_best > 1 {
+            best_score = best_score.or(path_score);
+            scores.path_rank = Some(rank);
+            scores.score_gap = best_score.zip(path_score).map(|(best, s)| best - s);
+        }
+        let intervals = IntervalTree::new(intervals, &hmm.interval_parents(&path));
+        Some(TypedRecord { path, query_indexes, intervals, scores, path_score })
+    }).collect()
+}
+
+
+/// Types the query, and with --both-strands its reverse complement too, keeping the strand whose
+/// best path scores higher. Intervals are always in the input orientation, but the state paths
+/// are on the strand that was typed. Fails if the query has characters that aren't bases
+fn type_stranded<'a>(hmm: &'a CompiledHmm, query: &str, qualities: Option<&[u8]>, args: &Args, null_model: &NullModel) -> Result<Vec<TypedRecord<'a>>, SequenceError> {
+    let mut forward = type_encoded(hmm, query, qualities, args, null_model)?;
+    if !args.both_strands {
+        return Ok(forward);
+    }
+    let reverse_qualities = qualities.map(|q| q.iter().rev().copied().collect::<Vec<_>>());
+    let mut reverse = type_encoded(hmm, &reverse_complement(query), reverse_qualities.as_deref(), args, null_model)?;
+
+    let best_score = |typed: &[TypedRecord]| typed.first()
+        .and_then(|t| t.path_score)
+        .unwrap_or(f64::NEG_INFINITY);
+    Ok(if best_score(&reverse) > best_score(&forward) {
+        let len = query.len();
+        for typed in reverse.iter_mut() {
+            typed.scores.strand = Some('-');
+            for node in typed.intervals.nodes.iter_mut() {
+                node.interval.reverse_strand(len);
+            }
+        }
+        reverse
+    } else {
+        for typed in forward.iter_mut() {
+            typed.scores.strand = Some('+');
+        }
+        forward
+    })
+}
+
+/// Encodes the query, with its qualities if there are any, and types it
+fn type_encoded<'a>(hmm: &'a CompiledHmm, query: &str, qualities: Option<&[u8]>, args: &Args, null_model: &NullModel) -> Result<Vec<TypedRecord<'a>>, SequenceError> {
+    let codes = try_sequence_to_bytes(query)?;
+    Ok(match qualities {
+        Some(q) => type_query(hmm, &QualityQuery::from_phred(codes, q)?, args, null_model),
+        None => type_query(hmm, &codes, args, null_model),
+    })
+}
+
+/// Header of the interval output, for the formats that have one
+fn write_intervals_header(writer: &mut impl Write, format: OutputFormat, write_hmm_state: bool, columns: &OutputColumns) -> io::Result<()> {
+    match format {
+        OutputFormat::Tsv => write_header(writer, write_hmm_state, columns),
+        OutputFormat::Bed | OutputFormat::Bed12 => bed::write_bed_header(writer),
+        OutputFormat::Gff3 => gff::write_gff_header(writer),
+        OutputFormat::Jsonl => Ok(()),
+    }
+}
+
+/// Writes the intervals of a typed record in the output format
+fn write_intervals(writer: &mut impl Write, format: OutputFormat, seqname: &str, typed: &TypedRecord, columns: &OutputColumns, colours: &bed::RegionColours) -> io::Result<()> {
+    match format {
+        OutputFormat::Tsv => tsvprint_intervals(writer, seqname, &typed.intervals, &typed.scores, columns),
+        OutputFormat::Bed => bed::write_bed(writer, seqname, &typed.intervals, &typed.scores, colours),
+        OutputFormat::Bed12 => bed::write_bed12(writer, seqname, &typed.intervals, &typed.scores),
+        OutputFormat::Gff3 => gff::write_gff3(writer, seqname, &typed.intervals, &typed.scores),
+        OutputFormat::Jsonl => unreachable!("JSON Lines are written per record, with write_json_record"),
+    }
+}
+
+/// Writes all the paths of a record as one JSON object, with the reason it was skipped if it was
+fn write_json_record(writer: &mut impl Write, record: &InputRecord, typed_paths: &Result<Vec<TypedRecord>, SequenceError>, model: &str, settings: Option<&HMMBuildSettings>) -> io::Result<()> {
+    let mut json = JsonRecord::new(&record.id, record.seq.len(), model, settings);
+    match typed_paths {
+        Ok(typed_paths) => for typed in typed_paths {
+            json.add_path(&typed.intervals, &typed.scores);
+        },
+        Err(e) => json.warnings.push(format!("Skipping record: {e}")),
+    }
+    jsonl::write_jsonl(writer, &json)
+}
+
+/// Fits the build parameters to the input records and writes them to the settings file
+fn train(args: &Args, settings: HMMBuildSettings, settings_file: &Path) -> Result<()> {
+    if !matches!(args.sva_model, SVAModelType::Simple) {
+        anyhow::bail!("Training is only supported for the simple model, the Dfam models have their own fitted parameters");
+    }
+    let queries = read_records(args.input_file(), false)?
+        .map(|r| {
+            let r = r?;
+            let query = String::from_utf8_lossy(&r.seq).to_uppercase();
+            try_sequence_to_bytes(&query).with_context(|| format!("Record {}", r.id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let build = |s: &HMMBuildSettings| match &args.hex_motifs {
+        Some(v) => sva::gen_sva_model_with_custom_hexseq(s, v),
+        None => sva::gen_sva_model(s),
+    };
+    let fitted = training::viterbi_train(build, &queries, settings, args.train_iterations, 1e-6)?;
+    let mut writer = open_write(Some(settings_file))?;
+    fitted.write_settings(&mut writer)?;
+    Ok(())
+}
+
+/// Reads the model file given with --model, or builds the model the model options describe
+fn load_model(args: &Args, settings: &HMMBuildSettings) -> Result<HMM> {
+    if let Some(f) = &args.model {
+        return HMM::read_model(BufReader::new(File::open(f)?)).with_context(|| format!("Reading {}", f.display()));
+    }
+    Ok(match args.sva_model {
+        SVAModelType::Simple => match &args.hex_motifs {
+            Some(v) => sva::gen_sva_model_with_custom_hexseq(settings, v),
+            None => sva::gen_sva_model(settings)
         },
-        SVAModelType::Complex => sva::gen_sva_model_with_innerseq(&settings),
-        SVAModelType::ComplexAllFamilies => sva::gen_sva_model_with_innerseq_all_families(&settings)
+        SVAModelType::Complex => sva::gen_sva_model_with_innerseq(settings),
+        SVAModelType::ComplexAllFamilies => sva::gen_sva_model_with_innerseq_all_families(settings)
+    })
+}
+
+fn load_null_model(args: &Args) -> Result<NullModel> {
+    Ok(match &args.null_composition {
+        Some(f) => NullModel::from_composition(&read_hmm_composition(f)?),
+        None => NullModel::uniform(),
+    })
+}
+
+/// Encodes the record and ranks the models for it
+fn rank_record<'a>(models: &'a [(String, CompiledHmm)], record: &InputRecord, score: ClassifyScore, null_model: &NullModel) -> Result<Vec<ModelRank<'a>>, SequenceError> {
+    let query = String::from_utf8_lossy(&record.seq).to_uppercase();
+    let codes = try_sequence_to_bytes(&query)?;
+    Ok(match &record.qual {
+        Some(q) => rank_models(models, &QualityQuery::from_phred(codes, q)?, score, null_model),
+        None => rank_models(models, &codes, score, null_model),
+    })
+}
+
+/// Scores every record against each of the competing models and writes their ranking
+fn classify(args: &Args, settings: &HMMBuildSettings, file: &Path, model_files: &[PathBuf], score: ClassifyScore) -> Result<()> {
+    let models = match model_files.is_empty() {
+        true => sva::gen_sva_subfamily_models(settings),
+        false => model_files.iter()
+            .map(|f| {
+                let name = f.file_stem().unwrap_or(f.as_os_str()).to_string_lossy().to_string();
+                let hmm = HMM::read_model(BufReader::new(File::open(f)?)).with_context(|| format!("Reading {}", f.display()))?;
+                Ok((name, hmm))
+            })
+            .collect::<Result<Vec<_>>>()?,
     };
+    let models = models.into_iter()
+        .map(|(name, hmm)| {
+            hmm.validate().with_context(|| format!("Model {name}"))?;
+            Ok((name, hmm.compile()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let null_model = load_null_model(args)?;
 
-    sva::gen_sva_model(&settings);
+    let mut writer = open_write(args.output_file.as_deref())?;
+    let score_name = match score {
+        ClassifyScore::LogLikelihood => "log_likelihood",
+        ClassifyScore::Bits => "bits",
+    };
+    write_classify_header(&mut writer, score_name)?;
+    let mut records = read_records(file, !args.ignore_qualities)?;
+    let mut total_i = 0;
+    loop {
+        let batch = records.by_ref().take(1000).collect::<Result<Vec<_>>>()?;
+        if batch.is_empty() {
+            break
+        }
+        total_i += batch.len();
+        eprint!("Record {}\r", total_i);
+        let results = match args.cores {
+            1 => batch.iter().map(|r| rank_record(&models, r, score, &null_model)).collect::<Vec<_>>(),
+            _ => batch.par_iter().map(|r| rank_record(&models, r, score, &null_model)).collect(),
+        };
+        for (record, ranks) in std::iter::zip(&batch, results) {
+            match ranks {
+                Ok(ranks) => tsvprint_model_ranks(&mut writer, &record.id, &ranks)?,
+                Err(e) => eprintln!("Skipping record {}: {}", record.id, e),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run(args: Args) -> Result<()> {
+    let settings = HMMBuildSettings::try_from(&args)?;
+    match &args.command {
+        Some(Command::Build { output }) => {
+            let hmm = load_model(&args, &settings)?;
+            hmm.validate()?;
+            hmm.write_model(open_write(Some(output))?)?;
+            return Ok(());
+        }
+        Some(Command::Dot { output, collapse_phmms }) => {
+            let hmm = load_model(&args, &settings)?;
+            dot::write_dot(&hmm, &mut open_write(Some(output))?, *collapse_phmms)?;
+            return Ok(());
+        }
+        Some(Command::Classify { file, models, score }) => {
+            return classify(&args, &settings, file, models, *score);
+        }
+        None => {},
+    }
+    if let Some(settings_file) = &args.train {
+        return train(&args, settings, settings_file);
+    }
+    let mut records = read_records(args.input_file(), !args.ignore_qualities)?;
+    if args.k_best == 0 {
+        anyhow::bail!("--k-best must be at least 1");
+    }
+    if args.k_best > 1 && matches!(args.decode, DecodeMethod::Posterior) {
+        anyhow::bail!("--k-best only works with viterbi decoding");
+    }
+    if args.write_hmm_state && !matches!(args.format, OutputFormat::Tsv) {
+        anyhow::bail!("--write-hmm-state only works with the tsv format");
+    }
+    let hmm = load_model(&args, &settings)?;
+    hmm.validate()?;
+    let mut hmm = hmm.compile().with_viterbi_memory_budget(args.viterbi_memory_mb << 20);
+    if let Some(beam) = args.beam() {
+        hmm = hmm.with_beam(beam);
+    }
+    let null_model = load_null_model(&args)?;
+    let columns = OutputColumns::from(&args);
+    let colours = bed::RegionColours::new(&hmm.region_names());
+    let json = matches!(args.format, OutputFormat::Jsonl);
+    let (model_name, model_settings) = match &args.model {
+        Some(f) => (f.display().to_string(), None),
+        None => (args.sva_model.to_possible_value().unwrap().get_name().to_string(), Some(&settings)),
+    };
 
     if args.cores == 1 {
         let mut writer = open_write(args.output_file.as_deref())?;
-        write_header(&mut writer, args.write_hmm_state, args.write_query_seq_state)?;
+        write_intervals_header(&mut writer, args.format, args.write_hmm_state, &columns)?;
         // TODO: Turn this into a parallel loop
-        for (i, record) in reader.records().enumerate() {
+        for (i, record) in records.enumerate() {
             eprint!("Record {}\r", i);
             let record = record?;
-            let query = std::str::from_utf8(record.seq()).unwrap().to_uppercase();
-            let (path, query_indexes) = hmm.query(&sequence_to_bytes(&query));
-            if args.write_hmm_state {
-                tsvprint_hmmstates(&mut writer, record.id(), &query, path, query_indexes)?;
-            } else {
-                let result = hmm::convert_to_intervals(path, query_indexes);
-                // sva::trim_loop_intervals(&mut result);
-                if args.write_query_seq_state {
-                    tsvprint_intervals_withseq(&mut writer, record.id(), &query, result)?;
+            let query = String::from_utf8_lossy(&record.seq).to_uppercase();
+            let typed_paths = type_stranded(&hmm, &query, record.qual.as_deref(), &args, &null_model);
+            if json {
+                write_json_record(&mut writer, &record, &typed_paths, &model_name, model_settings)?;
+                continue;
+            }
+            let typed_paths = match typed_paths {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Skipping record {}: {}", record.id, e);
+                    continue;
+                }
+            };
+            for typed in typed_paths {
+                if args.write_hmm_state {
+                    let typed_query = match typed.scores.strand {
+                        Some('-') => reverse_complement(&query),
+                        _ => query.clone(),
+                    };
+                    tsvprint_hmmstates(&mut writer, &record.id, &typed_query, typed.path, typed.query_indexes)?;
+                } else if args.write_query_seq_state && matches!(args.format, OutputFormat::Tsv) {
+                    // sva::trim_loop_intervals(&hmm, &mut tree);
+                    tsvprint_intervals_withseq(&mut writer, &record.id, &query, &typed.intervals, &typed.scores, &columns)?;
                 } else {
-                    tsvprint_intervals(&mut writer,record.id(), result)?;
+                    write_intervals(&mut writer, args.format, &record.id, &typed, &columns, &colours)?;
                 }
             }
         }
     } else {
         let mut writer = open_write(args.output_file.as_deref())?;
-        write_header(&mut writer, args.write_hmm_state, args.write_query_seq_state)?;
+        write_intervals_header(&mut writer, args.format, args.write_hmm_state, &columns)?;
         let mut total_i = 0;
 
-        let mut record = fasta::Record::new();
-
         let batch_size = 1000;
         loop {
-            let mut batch = Vec::with_capacity(batch_size);
-            for i in 0..batch_size {
-                // eprintln!("{i}");
-                reader.read(&mut record)?;
-                if record.is_empty() {
-                    break
-                }
-                
-                batch.push(record.clone());
-                total_i += 1;
-                eprint!("Record {}\r", total_i);
-            }
+            let batch = records.by_ref().take(batch_size).collect::<Result<Vec<_>>>()?;
             if batch.is_empty() {
                 break
             }
+            total_i += batch.len();
+            eprint!("Record {}\r", total_i);
 
             let results = batch.par_chunks(75).map(|vr| {
                 vr.iter().map(|r| {
-                    let query = std::str::from_utf8(r.seq()).unwrap().to_uppercase();
-                    let (path, query_indexes) = hmm.query(&sequence_to_bytes(&query));
-                    let result = hmm::convert_to_intervals(path, query_indexes);
-                    result
+                    let query = String::from_utf8_lossy(&r.seq).to_uppercase();
+                    type_stranded(&hmm, &query, r.qual.as_deref(), &args, &null_model)
                 }).collect::<Vec<_>>()
             }).flatten().collect::<Vec<_>>();
 
 
-            for (record, result )in std::iter::zip(batch, results) {
-                tsvprint_intervals(&mut writer,record.id(), result)?;
+            for (record, typed_paths) in std::iter::zip(batch, results) {
+                if json {
+                    write_json_record(&mut writer, &record, &typed_paths, &model_name, model_settings)?;
+                    continue;
+                }
+                let typed_paths = match typed_paths {
+                    Ok(t) => t,
+                    Err(e) => {
+                        eprintln!("Skipping record {}: {}", record.id, e);
+                        continue;
+                    }
+                };
+                for typed in typed_paths {
+                    write_intervals(&mut writer, args.format, &record.id, &typed, &columns, &colours)?;
+                }
             }
         }
     }
@@ -94,7 +435,50 @@ fn run(args: Args) -> Result<()> {
 
 fn main() {
     if let Err(e) = run(Args::parse()) {
-        eprintln!("Error: {}", e);
+        eprintln!("Error: {:#}", e);
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_strands_intervals() {
+        let seq = [
+            "GATTACAGGT",
+            &"CCCTCT".repeat(4),
+            "GCCTCTGCCCGGCCGCCCAGTCTGGGAAGTGAGGAGC",
+            "GCCCGGCCAGCCGCCCCGTCCGGGAGGAGGTGGGGGGGTCAGCCCCC",
+            "TTAGGCAT",
+        ].concat();
+        fn spans(typed: &[TypedRecord]) -> Vec<(String, usize, usize)> {
+            typed[0].intervals.nodes.iter()
+                .map(|n| (n.name.to_string(), n.interval.start, n.interval.stop))
+                .collect()
+        }
+
+        let forward_args = Args::parse_from(["sva_typer", "in.fa"]);
+        let settings = HMMBuildSettings::try_from(&forward_args).unwrap();
+        let hmm = load_model(&forward_args, &settings).unwrap().compile();
+        let null_model = NullModel::uniform();
+        let forward = type_stranded(&hmm, &seq, None, &forward_args, &null_model).unwrap();
+
+        let args = Args::parse_from(["sva_typer", "in.fa", "--both-strands"]);
+        let reverse = type_stranded(&hmm, &reverse_complement(&seq), None, &args, &null_model).unwrap();
+        assert_eq!(reverse[0].scores.strand, Some('-'));
+        // Same regions covering the same bases, in the coordinates of the reverse complement input
+        let len = seq.len();
+        let mirrored = spans(&forward).into_iter()
+            .map(|(name, start, stop)| (name, len - stop, len - start))
+            .collect::<Vec<_>>();
+        assert_eq!(spans(&reverse), mirrored);
+        let (_, start, stop) = &spans(&reverse)[2];
+        assert_eq!(&reverse_complement(&seq)[*start..*stop], reverse_complement("CCCTCT"));
+
+        let mut interval = Interval { start: 5, stop: usize::MAX, confidence: None, bits: None, alignment: None };
+        interval.reverse_strand(20);
+        assert_eq!((interval.start, interval.stop), (15, usize::MAX));
+    }
+}
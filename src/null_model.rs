@@ -0,0 +1,106 @@
+use std::f64::consts::LN_2;
+use crate::utils::{log_sum_exp, code_bases};
+
+/// HMMER-style background model: residues are drawn independently from a fixed composition and
+/// the length is geometric with p1 = L / (L + 1)
+#[derive(Clone, Debug)]
+pub struct NullModel {
+    /// Log probabilities of A, C, G, T
+    composition: Vec<f64>,
+}
+
+impl NullModel {
+    pub fn uniform() -> Self {
+        Self::from_composition(&[0.25, 0.25, 0.25, 0.25])
+    }
+
+    /// * `composition`: probabilities of A, C, G, T (e.g. the COMPO line of a .hmm file)
+    pub fn from_composition(composition: &[f64]) -> Self {
+        let total = composition.iter().sum::<f64>();
+        NullModel {
+            composition: composition.iter().map(|p| (p / total).ln()).collect(),
+        }
+    }
+
+    /// Log-likelihood of the whole query under the null model
+    pub fn log_likelihood(&self, query: &[u8]) -> f64 {
+        let (_, ln_end) = Self::length_probs(query.len());
+        self.span_log_likelihood(query, 0, query.len()) + ln_end
+    }
+
+    /// Log-odds score in bits of a whole-query log-likelihood (viterbi or forward) against the
+    /// null model
+    pub fn bits(&self, query: &[u8], score: f64) -> f64 {
+        to_bits(score - self.log_likelihood(query))
+    }
+
+    /// Log-likelihood of query[start..stop] under the null model, using the length distribution of
+    /// the full query so spans can be compared against partial path scores
+    pub fn span_log_likelihood(&self, query: &[u8], start: usize, stop: usize) -> f64 {
+        let (ln_p1, _) = Self::length_probs(query.len());
+        query[start..stop].iter()
+            .map(|base| match base {
+                4 => 0.0, // N is always emitted with probability 1, same as the HMM
+                0..4 => self.composition[*base as usize],
+                _ => log_sum_exp(code_bases(*base).iter().map(|b| self.composition[*b])),
+            })
+            .sum::<f64>()
+            + ln_p1 * (stop - start) as f64
+    }
+
+    /// ln(p1) for each residue and ln(1 - p1) for the end of the sequence
+    fn length_probs(len: usize) -> (f64, f64) {
+        let p1 = len as f64 / (len as f64 + 1.0);
+        (p1.ln(), (1.0 - p1).ln())
+    }
+}
+
+impl Default for NullModel {
+    fn default() -> Self {
+        Self::uniform()
+    }
+}
+
+/// Converts a log-odds score in nats to bits
+pub fn to_bits(score: f64) -> f64 {
+    score / LN_2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{HMMBuildSettings, create_HMM_from_motifs};
+    use crate::utils::sequence_to_bytes;
+
+    #[test]
+    fn uniform_null() {
+        let null_model = NullModel::uniform();
+        let query = sequence_to_bytes("ACGTN");
+        let p1 = 5.0 / 6.0_f64;
+        let expected = 4.0 * 0.25_f64.ln() + 5.0 * p1.ln() + (1.0 - p1).ln();
+        assert!((null_model.log_likelihood(&query) - expected).abs() < 1e-9);
+        assert!((null_model.bits(&query, expected)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bit_score_test() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let hmm = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2"], &settings, "test");
+        let null_model = NullModel::uniform();
+
+        let query = sequence_to_bytes(&format!("{}{}{}", motifs[0], motifs[1], motifs[1]));
+        let compiled = hmm.compile();
+        let (path, query_indexes) = compiled.query(&query);
+        let scores = compiled.path_scores(&query, &path, &query_indexes);
+        assert!(*scores.last().unwrap() <= compiled.forward(&query));
+        assert!(null_model.bits(&query, *scores.last().unwrap()) > 0.0);
+
+        let mut intervals = compiled.convert_to_intervals(path.clone(), query_indexes.clone());
+        compiled.score_intervals(&mut intervals, &query, &path, &query_indexes, &null_model);
+        assert!(intervals.iter().filter(|(n, _)| *n != "test").all(|(_, i)| i.bits.unwrap() > 0.0));
+
+        let junk = sequence_to_bytes("TTTTTTTTTTTTTTTTTTTTTTTTT");
+        assert!(null_model.bits(&junk, hmm.forward(&junk)) < 0.0);
+    }
+}
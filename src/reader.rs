@@ -1,5 +1,6 @@
 use std::{io::{self, BufRead, BufReader}, num::{ParseFloatError, ParseIntError}, ops::Neg, path::Path};
-use crate::hmm::{HMM, HMMState};
+use crate::hmm::{HMM, HMMState, StateAnnotation, StateRole};
+use crate::builder::AlignmentMode;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -36,7 +37,9 @@ fn from_vectors(
     del_to_del_probs: Vec<f64>,
                                                
     match_emit_vecs: Vec<Vec<f64>>,
-    ins_emit_vecs: Vec<Vec<f64>>
+    ins_emit_vecs: Vec<Vec<f64>>,
+    alignment_mode: AlignmentMode,
+    first_position: usize,
 ) -> HMM {
 
 
@@ -65,24 +68,32 @@ fn from_vectors(
         }
         None => "".to_string()
     };
+    // Positions are in the whole consensus, even if only a slice of it is read
+    let annotation = |role: StateRole, i: Option<usize>| {
+        StateAnnotation::phmm(prefix.trim_end_matches('_'), role, i.map(|i| i + first_position))
+    };
+
+    let hmm_length = match_to_match_probs.len();
+    let local = alignment_mode == AlignmentMode::Local;
+    let entry = |i: usize| AlignmentMode::entry_prob(hmm_length, i).ln();
+    // Log share of a match state's transitions that don't go straight to the end
+    let stay = |i: usize| (1.0 - alignment_mode.exit_prob(hmm_length, i)).ln();
 
-    hmm.add_state(HMMState::empty_state(format!("{prefix}start")));
+    hmm.add_state(HMMState::empty_state(format!("{prefix}start")).with_annotation(annotation(StateRole::Start, None)));
 
     hmm.add_state(HMMState::new_with_logprob(
         format!("{prefix}M0"),
         Some(match_emit_vecs[0].clone()),
         vec![format!("{prefix}start")],
-        vec![0.0],
-    ));
+        vec![if local { entry(0) } else { 0.0 }],
+    ).with_annotation(annotation(StateRole::Match, Some(0))));
 
     hmm.add_state(HMMState::new_with_logprob(
         format!("{prefix}I0"),
         Some(ins_emit_vecs[0].clone()),
         vec![format!("{prefix}M0"), format!("{prefix}I0")],
-        vec![match_to_ins_probs[0], ins_to_ins_probs[0]]
-    ));
-
-    let hmm_length = match_to_match_probs.len();
+        vec![match_to_ins_probs[0] + stay(0), ins_to_ins_probs[0]]
+    ).with_annotation(annotation(StateRole::Insert, Some(0))));
 
     for i in 1..hmm_length {
         // Add matches
@@ -92,7 +103,7 @@ fn from_vectors(
         ];
 
         let mut match_prev_state_probs = vec![
-            match_to_match_probs[i-1],
+            match_to_match_probs[i-1] + stay(i-1),
             ins_to_match_probs[i-1],
         ];
 
@@ -100,26 +111,30 @@ fn from_vectors(
             match_prev_states.push(format!("{prefix}D{}", i-1));
             match_prev_state_probs.push(del_to_match_probs[i-1]);
         }
+        if local {
+            match_prev_states.push(format!("{prefix}start"));
+            match_prev_state_probs.push(entry(i));
+        }
 
         hmm.add_state(HMMState::new_with_logprob(
             format!("{prefix}M{i}"),
             Some(match_emit_vecs[i].clone()),
             match_prev_states,
             match_prev_state_probs
-        ));
+        ).with_annotation(annotation(StateRole::Match, Some(i))));
 
         // Add Insertion State
         hmm.add_state(HMMState::new_with_logprob(
             format!("{prefix}I{i}"),
             Some(ins_emit_vecs[i].clone()),
             vec![format!("{prefix}M{i}"), format!("{prefix}I{i}")],
-            vec![match_to_ins_probs[i], ins_to_ins_probs[i]],
-        ));
+            vec![match_to_ins_probs[i] + stay(i), ins_to_ins_probs[i]],
+        ).with_annotation(annotation(StateRole::Insert, Some(i))));
         
 
         let mut del_prev_states = vec![format!("{prefix}M{}", i - 1)];
         let mut del_prev_state_probs =
-            vec![match_to_del_probs[i-1]];
+            vec![match_to_del_probs[i-1] + stay(i-1)];
 
         if i > 1 {
             // Only add previous deletion state if i > 1
@@ -133,30 +148,44 @@ fn from_vectors(
             None,
             del_prev_states,
             del_prev_state_probs,
-        ));
+        ).with_annotation(annotation(StateRole::Delete, Some(i))));
+    }
+    let mut end_prev_states = vec![
+        format!("{prefix}M{}", hmm_length-1),
+        format!("{prefix}I{}", hmm_length-1),
+        format!("{prefix}D{}", hmm_length-1)
+    ];
+    // Technically this is in the hmm file, but if the vectors have been subset, the transition
+    // probabilities won't add to 1
+    let mut end_prev_state_probs = vec![
+        (1.0 - match_to_ins_probs[hmm_length-1].exp()).ln(),
+        ins_to_match_probs[hmm_length-1],
+        0.0
+    ];
+    if local {
+        for i in 0..hmm_length-1 {
+            end_prev_states.push(format!("{prefix}M{i}"));
+            end_prev_state_probs.push(alignment_mode.exit_prob(hmm_length, i).ln());
+        }
     }
     hmm.add_state(HMMState::new_with_logprob(
         format!("{prefix}end"),
         None,
-        vec![
-            format!("{prefix}M{}", hmm_length-1),
-            format!("{prefix}I{}", hmm_length-1),
-            format!("{prefix}D{}", hmm_length-1)
-        ],
-        // Technically this is in the hmm file, but if the vectors have been subset, the transition
-        // probabilities won't add to 1
-        vec![
-            (1.0 - match_to_ins_probs[hmm_length-1].exp()).ln(),
-            ins_to_match_probs[hmm_length-1],
-            0.0
-        ]
-    ));
+        end_prev_states,
+        end_prev_state_probs,
+    ).with_annotation(annotation(StateRole::End, None)));
     hmm
 
 
 }
  
-fn read_lines(reader: &mut impl BufRead, prefix: Option<&str>, start_pos: Option<usize>, end_pos: Option<usize>) -> Result<HMM, ReaderError> {
+fn read_lines(
+    reader: &mut impl BufRead,
+    prefix: Option<&str>,
+    start_pos: Option<usize>,
+    end_pos: Option<usize>,
+    alignment_mode: AlignmentMode
+) -> Result<HMM, ReaderError> {
     let mut match_to_match_probs = Vec::new();
     let mut match_to_ins_probs = Vec::new();
     let mut match_to_del_probs = Vec::new();
@@ -256,17 +285,49 @@ fn read_lines(reader: &mut impl BufRead, prefix: Option<&str>, start_pos: Option
         del_to_match_probs,
         del_to_del_probs,
         match_emit_vecs,
-        ins_emit_vecs
+        ins_emit_vecs,
+        alignment_mode,
+        start
     );
     Ok(hmm)
 }
 
-pub fn read_hmm_file(file: &Path, prefix: Option<&str>, start_pos: Option<usize>, end_pos: Option<usize>) -> Result<HMM, ReaderError> {
+/// Reads a HMMER3 .hmm file, optionally only the match states from `start_pos` to `end_pos`
+/// * `file`: 
+/// * `prefix`: 
+/// * `start_pos`: 
+/// * `end_pos`: 
+/// * `alignment_mode`: global or local entry and exit
+pub fn read_hmm_file(
+    file: &Path,
+    prefix: Option<&str>,
+    start_pos: Option<usize>,
+    end_pos: Option<usize>,
+    alignment_mode: AlignmentMode
+) -> Result<HMM, ReaderError> {
     let file = std::fs::File::open(file)?;
     let mut b = BufReader::new(file);
-    read_lines(&mut b, prefix, start_pos, end_pos)
+    read_lines(&mut b, prefix, start_pos, end_pos, alignment_mode)
 }
 
+/// Reads the background composition of A, C, G, T from the COMPO line of a .hmm file, converted
+/// from negative log probabilities to probabilities
+pub fn read_hmm_composition(file: &Path) -> Result<Vec<f64>, ReaderError> {
+    let file = std::fs::File::open(file)?;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let splits = line.split_whitespace().collect::<Vec<_>>();
+        if splits.first() == Some(&"COMPO") {
+            if splits.len() < 5 {
+                return Err(ReaderError::Parse(format!("COMPO line has too few fields: {line}")));
+            }
+            return splits[1..5].iter().map(|s| s.parse::<f64>().map(|p| (-p).exp()).map_err(
+                |e| ReaderError::ParseFloat { str: String::from(*s), source: e}
+            )).collect::<Result<Vec<_>, _>>();
+        }
+    }
+    Err(ReaderError::Parse("No COMPO line found".to_string()))
+}
 
 mod test {
 
@@ -274,7 +335,7 @@ mod test {
     #[test]
     fn hmm_reader() {
         let file = Path::new("test/DF000001067.hmm");
-        match read_hmm_file(file, Some("Test"), None, None) {
+        match read_hmm_file(file, Some("Test"), None, None, AlignmentMode::Global) {
             Ok(hmm) => {
                 hmm.check_valid()
             },
@@ -285,7 +346,7 @@ mod test {
     #[test]
     fn hmm_reader_subset() {
         let file = Path::new("test/DF000001067.hmm");
-        match read_hmm_file(file, Some("Test"), Some(5), Some(52)) {
+        match read_hmm_file(file, Some("Test"), Some(5), Some(52), AlignmentMode::Global) {
             Ok(hmm) => {
                 hmm.check_valid()
             },
@@ -293,4 +354,30 @@ mod test {
         }
     }
 
+    #[test]
+    fn local_hmm_reader() {
+        let file = Path::new(env!("CARGO_MANIFEST_DIR")).join("ref/DF000001067.hmm");
+        let hmm = read_hmm_file(&file, Some("Test"), Some(70), Some(434), AlignmentMode::Local).unwrap();
+        hmm.validate().unwrap();
+
+        // A slice of the consensus should align to the same slice of the model
+        let map = hmm.get_index_map();
+        let consensus = (100..160).map(|i| {
+            let emission = hmm.states[map[&format!("Test_M{i}")]].emission().unwrap();
+            (0..4).max_by(|a, b| emission[*a].total_cmp(&emission[*b])).unwrap() as u8
+        }).collect::<Vec<_>>();
+        let (path, _) = hmm.query(&consensus);
+        let matches = path.iter().filter(|s| s.starts_with("Test_M")).collect::<Vec<_>>();
+        assert_eq!(matches.first(), Some(&&"Test_M100"));
+        assert_eq!(matches.last(), Some(&&"Test_M159"));
+    }
+
+    #[test]
+    fn hmm_composition() {
+        let file = Path::new(env!("CARGO_MANIFEST_DIR")).join("ref/DF000001067.hmm");
+        let composition = read_hmm_composition(&file).unwrap();
+        assert_eq!(composition.len(), 4);
+        assert!((composition.iter().sum::<f64>() - 1.0).abs() < 1e-3);
+    }
+
 }
@@ -0,0 +1,135 @@
+//! Vectorised viterbi for the match and insert states of pHMM blocks. These states only depend on
+//! the previous column, and each has at most `SLOTS` predecessors (start, M, I and D of the
+//! previous position for a match state), so a column of them can be scored several states at a
+//! time. Every lane does the same additions in the same order and compares the predecessors in
+//! the same order as the scalar viterbi, so the scores and tracebacks are bit-for-bit identical.
+
+use crate::utils::N_CODES;
+
+/// Predecessors per state in the lanes. States with fewer are padded with impossible transitions
+pub(crate) const SLOTS: usize = 4;
+/// Marks a cell with no previous state in the traceback, same as in `compiled`
+const NO_STATE: u32 = u32::MAX;
+
+/// Structure-of-arrays copy of the states that get the vectorised column
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PhmmLanes {
+    pub(crate) states: Vec<u32>,
+    /// Slot-major, so the predecessors in one slot of consecutive states are contiguous
+    pred_states: Vec<u32>,
+    pred_trans: Vec<f64>,
+}
+
+impl PhmmLanes {
+    /// * `states`: emitting states with at most `SLOTS` predecessors, each given in the order
+    ///   the scalar viterbi goes through them
+    pub(crate) fn new(states: Vec<(u32, Vec<(u32, f64)>)>) -> Self {
+        let n = states.len();
+        let mut pred_states = vec![0; SLOTS * n];
+        let mut pred_trans = vec![f64::NEG_INFINITY; SLOTS * n];
+        for (j, (state_i, preds)) in states.iter().enumerate() {
+            assert!(preds.len() <= SLOTS, "Too many predecessors for a lane");
+            for slot in 0..SLOTS {
+                // Padding reads a real score (the state's own) but can never win with -inf
+                let (prev_i, trans_lp) = preds.get(slot).copied().unwrap_or((*state_i, f64::NEG_INFINITY));
+                pred_states[slot * n + j] = prev_i;
+                pred_trans[slot * n + j] = trans_lp;
+            }
+        }
+        PhmmLanes { states: states.into_iter().map(|(s, _)| s).collect(), pred_states, pred_trans }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Scores every state in the lanes for a column, from the scores of the previous one
+    /// * `emissions`: states x codes log emission table
+    /// * `code`: query code emitted in this column
+    /// * `prev`:
+    /// * `cur`:
+    /// * `trace`:
+    pub(crate) fn viterbi_column(&self, emissions: &[f64], code: u8, prev: &[f64], cur: &mut [f64], trace: &mut [u32]) {
+        #[cfg(target_arch = "x86_64")]
+        if std::arch::is_x86_feature_detected!("avx2") {
+            // Safety: AVX2 is available, and every state and predecessor index is a valid state,
+            // so all the gathers are in bounds of prev and emissions
+            let done = unsafe { self.viterbi_column_avx2(emissions, code, prev, cur, trace) };
+            self.viterbi_column_scalar(done, emissions, code, prev, cur, trace);
+            return;
+        }
+        self.viterbi_column_scalar(0, emissions, code, prev, cur, trace);
+    }
+
+    /// Scalar version of the lanes from `first` on, used for the states left over after the last
+    /// full vector and without AVX2
+    fn viterbi_column_scalar(&self, first: usize, emissions: &[f64], code: u8, prev: &[f64], cur: &mut [f64], trace: &mut [u32]) {
+        let n = self.states.len();
+        for j in first..n {
+            let state_i = self.states[j] as usize;
+            let ln_em = emissions[state_i * N_CODES + code as usize];
+            let mut best_state = NO_STATE;
+            let mut best_score = f64::NEG_INFINITY;
+            for slot in 0..SLOTS {
+                let score = prev[self.pred_states[slot * n + j] as usize] + self.pred_trans[slot * n + j] + ln_em;
+                if score > best_score {
+                    best_score = score;
+                    best_state = self.pred_states[slot * n + j];
+                }
+            }
+            if best_state != NO_STATE {
+                cur[state_i] = best_score;
+            }
+            trace[state_i] = best_state;
+        }
+    }
+
+    /// Four states at a time. Returns how many states it did
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn viterbi_column_avx2(&self, emissions: &[f64], code: u8, prev: &[f64], cur: &mut [f64], trace: &mut [u32]) -> usize {
+        use std::arch::x86_64::*;
+
+        let n = self.states.len();
+        let n_codes = _mm_set1_epi32(N_CODES as i32);
+        let code = _mm_set1_epi32(code as i32);
+        let mut j = 0;
+        while j + 4 <= n {
+            let states = _mm_loadu_si128(self.states.as_ptr().add(j) as *const __m128i);
+            let em_index = _mm_add_epi32(_mm_mullo_epi32(states, n_codes), code);
+            let ln_em = _mm256_i32gather_pd::<8>(emissions.as_ptr(), em_index);
+
+            let mut best_score = _mm256_set1_pd(f64::NEG_INFINITY);
+            let mut best_state = _mm256_set1_epi64x(NO_STATE as i64);
+            for slot in 0..SLOTS {
+                let offset = slot * n + j;
+                let prev_states = _mm_loadu_si128(self.pred_states.as_ptr().add(offset) as *const __m128i);
+                let prev_scores = _mm256_i32gather_pd::<8>(prev.as_ptr(), prev_states);
+                let trans_lp = _mm256_loadu_pd(self.pred_trans.as_ptr().add(offset));
+                let score = _mm256_add_pd(_mm256_add_pd(prev_scores, trans_lp), ln_em);
+                let better = _mm256_cmp_pd::<_CMP_GT_OQ>(score, best_score);
+                best_score = _mm256_blendv_pd(best_score, score, better);
+                best_state = _mm256_castpd_si256(_mm256_blendv_pd(
+                    _mm256_castsi256_pd(best_state),
+                    _mm256_castsi256_pd(_mm256_cvtepu32_epi64(prev_states)),
+                    better,
+                ));
+            }
+
+            let mut scores = [0.0; 4];
+            let mut best_states = [0u64; 4];
+            _mm256_storeu_pd(scores.as_mut_ptr(), best_score);
+            _mm256_storeu_si256(best_states.as_mut_ptr() as *mut __m256i, best_state);
+            for lane in 0..4 {
+                let state_i = self.states[j + lane] as usize;
+                let best_state = best_states[lane] as u32;
+                if best_state != NO_STATE {
+                    cur[state_i] = scores[lane];
+                }
+                trace[state_i] = best_state;
+            }
+            j += 4;
+        }
+        j
+    }
+}
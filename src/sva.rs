@@ -1,6 +1,7 @@
-use std::{env, path::{Path, PathBuf}};
+use std::{collections::HashSet, env, path::{Path, PathBuf}};
 
-use crate::hmm::HMM;
+use crate::compiled::CompiledHmm;
+use crate::hmm::{HMM, StateRole};
 use crate::builder::*;
 use crate::utils::*;
 use crate::reader::*;
@@ -28,18 +29,20 @@ const SVA_TYPES: &[(&str, &str, usize, usize, usize)] = &[
 pub fn gen_sva_model(settings: &HMMBuildSettings) -> HMM {
 
 
-    let hexamer_hmm = create_HMM_from_motifs(
+    let hexamer_hmm = create_HMM_from_motifs_with_copies(
         &[HEXAMER_REPEAT],
         &["hex"],
         settings,
-        "hexamer_region"
+        "hexamer_region",
+        settings.hexamer_copies
     );
 
-    let vntr_hmm = create_HMM_from_motifs(
+    let vntr_hmm = create_HMM_from_motifs_with_copies(
         VNTR_REPEATS,
         &["VNTR_1", "VNTR_2", "VNTR_3"],
         settings,
-        "VNTR_region"
+        "VNTR_region",
+        settings.vntr_copies
     );
 
     let skip1 = create_skip_state(settings, Some("skip1"));
@@ -59,18 +62,20 @@ pub fn gen_sva_model_with_custom_hexseq(settings: &HMMBuildSettings, hex_motifs:
     // I'm sure there's a less stupid way of doing this
     let motif_names_ref = motif_names.iter().map(|s| s.as_str()).collect::<Vec<_>>();
 
-    let hexamer_hmm = create_HMM_from_motifs(
+    let hexamer_hmm = create_HMM_from_motifs_with_copies(
         &hex_motifs.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
         &motif_names_ref,
         settings,
-        "hexamer_region"
+        "hexamer_region",
+        settings.hexamer_copies
     );
 
-    let vntr_hmm = create_HMM_from_motifs(
+    let vntr_hmm = create_HMM_from_motifs_with_copies(
         VNTR_REPEATS,
         &["VNTR_1", "VNTR_2", "VNTR_3"],
         settings,
-        "VNTR_region"
+        "VNTR_region",
+        settings.vntr_copies
     );
 
     let skip1 = create_skip_state(settings, Some("skip1"));
@@ -90,18 +95,20 @@ pub fn sva_hmm_dir() -> PathBuf {
 }
 
 pub fn gen_sva_model_with_innerseq_all_families(settings: &HMMBuildSettings) -> HMM {
-    let hexamer_hmm = create_HMM_from_motifs(
+    let hexamer_hmm = create_HMM_from_motifs_with_copies(
         &[HEXAMER_REPEAT],
         &["hex"],
         settings,
-        "hexamer_region"
+        "hexamer_region",
+        settings.hexamer_copies
     );
 
-    let vntr_hmm = create_HMM_from_motifs(
+    let vntr_hmm = create_HMM_from_motifs_with_copies(
         VNTR_REPEATS,
         &["VNTR_1", "VNTR_2", "VNTR_3"],
         settings,
-        "VNTR_region"
+        "VNTR_region",
+        settings.vntr_copies
     );
 
     let hmm_dir = sva_hmm_dir();
@@ -113,7 +120,8 @@ pub fn gen_sva_model_with_innerseq_all_families(settings: &HMMBuildSettings) ->
                     &hmm_dir.join(*path),
                     Some(format!("{elem_type}_ALU").as_str()), 
                     Some(*alu_start), 
-                    Some(*alu_end))
+                    Some(*alu_end),
+                    settings.alignment_mode)
             }).collect::<Result<Vec<_>,_>>().unwrap_or_else(|e| panic!("{e}")),
         "ALU"
     );
@@ -125,7 +133,8 @@ pub fn gen_sva_model_with_innerseq_all_families(settings: &HMMBuildSettings) ->
                     &hmm_dir.join(*path), 
                     Some(format!("{elem_type}_SINE").as_str()), 
                     Some(*sine_start), 
-                    None)
+                    None,
+                    settings.alignment_mode)
             }).collect::<Result<Vec<_>,_>>().unwrap_or_else(|e| panic!("{e}")),
         "SINE"
     );
@@ -138,21 +147,35 @@ pub fn gen_sva_model_with_innerseq_all_families(settings: &HMMBuildSettings) ->
 }
 
 pub fn gen_sva_model_with_innerseq(settings: &HMMBuildSettings) -> HMM {
-    let hexamer_hmm = create_HMM_from_motifs(
+    gen_sva_subfamily_model(settings, SVA_TYPES[0])
+}
+
+/// One model per SVA subfamily, named after it, for scoring records against each of them
+/// separately
+pub fn gen_sva_subfamily_models(settings: &HMMBuildSettings) -> Vec<(String, HMM)> {
+    SVA_TYPES.iter()
+        .map(|sva_type| (sva_type.0.to_string(), gen_sva_subfamily_model(settings, *sva_type)))
+        .collect()
+}
+
+fn gen_sva_subfamily_model(settings: &HMMBuildSettings, sva_type: (&str, &str, usize, usize, usize)) -> HMM {
+    let hexamer_hmm = create_HMM_from_motifs_with_copies(
         &[HEXAMER_REPEAT],
         &["hex"],
         settings,
-        "hexamer_region"
+        "hexamer_region",
+        settings.hexamer_copies
     );
 
-    let vntr_hmm = create_HMM_from_motifs(
+    let vntr_hmm = create_HMM_from_motifs_with_copies(
         VNTR_REPEATS,
         &["VNTR_1", "VNTR_2", "VNTR_3"],
         settings,
-        "VNTR_region"
+        "VNTR_region",
+        settings.vntr_copies
     );
 
-    let (elem_type, path, alu_start, alu_end, sine_start) = SVA_TYPES[0];
+    let (elem_type, path, alu_start, alu_end, sine_start) = sva_type;
 
     let hmm_dir = sva_hmm_dir();
 
@@ -160,13 +183,15 @@ pub fn gen_sva_model_with_innerseq(settings: &HMMBuildSettings) -> HMM {
                     &hmm_dir.join(path),
                     Some(format!("{elem_type}_ALU").as_str()), 
                     Some(alu_start), 
-                    Some(alu_end)).unwrap();
+                    Some(alu_end),
+                    settings.alignment_mode).unwrap();
 
     let sine_region = read_hmm_file(
                     &hmm_dir.join(path), 
                     Some(format!("{elem_type}_SINE").as_str()), 
                     Some(sine_start), 
-                    None
+                    None,
+                    settings.alignment_mode
     ).unwrap();
 
 
@@ -177,54 +202,38 @@ pub fn gen_sva_model_with_innerseq(settings: &HMMBuildSettings) -> HMM {
 }
 
 
-pub fn trim_loop_intervals(final_intervals: &mut Vec<(&str, Interval)>) {
-    // This is overly verbose because of borrowing rules
-    let (_, hex_interval) = final_intervals.iter().find(|(n, _)| n == &"hexamer_region").unwrap();
-    let hex_start = hex_interval.start;
-    let hex_stop = hex_interval.stop;
-
-    let (_, vntr_interval) = final_intervals.iter().find(|(n, _)| n == &"VNTR_region").unwrap();
-    let vntr_start = vntr_interval.start;
-    let vntr_stop = vntr_interval.stop;
-
-
-    let mut new_hex_start = hex_start;
-    let mut new_hex_stop = hex_stop;
-    let mut new_vntr_start = vntr_start;
-    let mut new_vntr_stop = vntr_stop;
-    for (n, int) in final_intervals.iter_mut() {
-        if n == &"hexamer_region_skip" {
-            if int.start == hex_start {
-                *n = "skip";
-                new_hex_start = int.stop;
+/// Renames the skips at either end of a region (e.g. the `hexamer_region_skip` of a loop that
+/// doesn't start or end with a full copy) to `skip`, and trims the region to the intervals between
+/// them
+/// * `hmm`:
+/// * `intervals`:
+pub fn trim_loop_intervals(hmm: &CompiledHmm, intervals: &mut IntervalTree) {
+    let skip_regions = (0..hmm.n_states())
+        .map(|i| hmm.annotation(i))
+        .filter(|a| a.role == StateRole::Skip)
+        .map(|a| a.region.as_str())
+        .collect::<HashSet<_>>();
+
+    for node_i in 0..intervals.nodes.len() {
+        let (start, stop) = (intervals.nodes[node_i].interval.start, intervals.nodes[node_i].interval.stop);
+        let (mut new_start, mut new_stop) = (start, stop);
+        for child_i in intervals.nodes[node_i].children.clone() {
+            let child = &mut intervals.nodes[child_i];
+            if !skip_regions.contains(child.name) {
+                continue;
             }
-            if int.stop == hex_stop {
-                *n = "skip";
-                new_hex_stop = int.start;
-            }
-        }
-        if n == &"VNTR_region_skip" {
-            if int.start == vntr_start {
-                *n = "skip";
-                new_vntr_start = int.stop;
+            if child.interval.start == start {
+                child.name = "skip";
+                new_start = child.interval.stop;
             }
-            if int.stop == vntr_stop {
-                *n = "skip";
-                new_vntr_stop = int.start;
+            if child.interval.stop == stop {
+                child.name = "skip";
+                new_stop = child.interval.start;
             }
         }
+        intervals.nodes[node_i].interval.start = new_start;
+        intervals.nodes[node_i].interval.stop = new_stop;
     }
-    for (n, int) in final_intervals.iter_mut() {
-        if n == &"hexamer_region" {
-            int.start = new_hex_start;
-            int.stop = new_hex_stop;
-        }
-        if n == &"VNTR_region" {
-            int.start = new_vntr_start;
-            int.stop = new_vntr_stop;
-        }
-    }
-
 }
 
 
@@ -232,6 +241,7 @@ pub fn trim_loop_intervals(final_intervals: &mut Vec<(&str, Interval)>) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hmm::{ModelFileError, MODEL_FORMAT_VERSION};
     // use crate::utils::*;
 
     const SVA_F_SEQ: &str = "CTCTCCCTCTCCCTCTCCCTCTCCCTCTCCCTCTCCCTCTCCCTCTCCCCTCTTTCCACGGTCTCCCTCTCATGCGGAGCCGAAGCTGGACTGTACTGCTGCCATCTCGGCTCACTGCAACCTCCCTGCCTGATTCTCCTGCCTCAGCCTGCCGAGTGCCTGCGATTGCAGGCACGCGCCGCCACGCCTGACTGGTTTTGGTGGAGACGGGGTTTCGCTGTGTTGGCCGGGCCGGTCTCCAGCCCCTAACCGCGAGTGATCCGCCAGCCTCGGCCTCCCGAGGTGCCGGGATTGCAGACGGAGTCTCGTTCACTCAGTGCTCAATGGTGCCCAGGCTGGAGTGCAGTGGCGTGATCTCGGCTCGCTACAACCTACACCTCCCAGCCGCCTGCCTTGGCCTCCCAAAGTGCCGAGATTGCAGCCTCTGCCCGGCCGCCGCCCCGTCTGGGAGGTGAGGAGCGCCTCTGCCCGGCCGCCCATCGTCTGGGANGTGAGGAGCCCCTCTGCCCGGCCGCCCCGTCTGGGAGGTGAGGAGCGCCTCCGCCCGGCCGCCGCCCCGTCCGGGAGGTGAGGAGCGTCTCCGCCCGGCCGCCCNCCGTCCGGGANGTGAGGAGCGCCTCCGCCCGGCCGCCCCGTCCGGGANGTGAGGAGCGCCTCCGCCCGGCCAGCCGCCCCGTCCGGGAGGTGGGGGGGTCAGCCCCCCGCCCGGCCAGCCGCCCCGTCCGGGAGGAGGTGGGGGGGTCAGCCCCCCGCCCGGCCAGCCGCCCCGTCCGGGAGGTGAGGGGCGCCTCTGCCCGGCCGCCCCTACTGGGAAGTGAGGAGCCCCTCTGCCCGGCCACCGCCCCGTCCGGGAGGTGTGCCCAACAGCTCATTGAGAACGGGCCAGGATGACAATGGCGGCTTTGTGGAATAGAAAGGCGGGAAAGGTGGGGAAAAGATTGAGAAATCGGATGGTTGCCGTGTCTGTGTAGAAAGAAGTAGACATGGGAGACTTTTCATTTTGTTCTGCACTAAGAAAAATTCCTCTGCCTTGGGATCCTGTTGATCTGTGACCTTACCCCCAACCCTGTGCTCTCTGAAACATGTGCTGTGTCCACTCAGGGTTAAATGGATTAAGGGCGGTGCAAGATGTGCTTTGTTAAACAGATGCTTGAAGGCAGCATGCTCGTTAAGAGTCATCACCAATCCCTAATCTCAAGTAATCAGGGACACAAACACTGCGGAAGGCCGCAGGGTCCTCTGCCTAGGAAAACCAGAGACCTTTGTTCACTTGTTTATCTGCTGACCTTCCCTCCACTATTGTCCCATGACCCTGCCAAATCCCCCTCTGTGAGAAACACCCAAGAATTATCAATAAAAAAAATNAAAAAAAAAA";
@@ -242,12 +252,96 @@ mod tests {
         let hmm = gen_sva_model(&settings);
         hmm.check_valid();
         let mut result = hmm.query(&sequence_to_bytes(SVA_F_SEQ));
-        // trim_loop_intervals(&mut result);
+        // trim_loop_intervals(&hmm.compile(), &mut tree);
         let mut writer = std::io::stdout();
         // pprint_intervals(&mut writer, result);
         // panic!();
     }
 
+    #[test]
+    fn sampled_sva_regions() {
+        let settings = HMMBuildSettings::default();
+        let hmm = gen_sva_model(&settings);
+        let compiled = hmm.compile();
+        for seed in 0..10 {
+            let (sequence, path, query_indexes) = hmm.sample(seed);
+            assert_eq!(hmm.sample(seed).0, sequence);
+            assert_eq!(query_indexes.last(), Some(&sequence.len()));
+
+            let truth = hmm.convert_to_intervals(path, query_indexes);
+            let (path, query_indexes) = compiled.query(&sequence);
+            let decoded = hmm.convert_to_intervals(path, query_indexes);
+            // The decoder should find the true region boundaries to within a couple of bases
+            for region in ["hexamer_region", "VNTR_region"] {
+                let (_, t) = truth.iter().find(|(n, _)| *n == region).unwrap();
+                let (_, d) = decoded.iter().find(|(n, _)| *n == region).unwrap();
+                assert!(t.start.abs_diff(d.start) <= 2 && t.stop.abs_diff(d.stop) <= 2, "seed {seed}: {region} {t:?} {d:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn trimmed_loop_skips() {
+        let settings = HMMBuildSettings::default();
+        let hmm = gen_sva_model(&settings).compile();
+        let interval = |start, stop| Interval { start, stop, confidence: None, bits: None, alignment: None };
+        let intervals = vec![
+            ("hexamer_region", interval(0, 20)),
+            ("hexamer_region_skip", interval(0, 3)),
+            ("hex", interval(3, 9)),
+            ("hexamer_region_skip", interval(9, 11)),
+            ("hex", interval(11, 17)),
+            ("hexamer_region_skip", interval(17, 20)),
+            ("skip2_skip", interval(20, 25)),
+        ];
+        let mut tree = IntervalTree::new(intervals, &[None, Some(0), Some(0), Some(0), Some(0), Some(0), None]);
+        trim_loop_intervals(&hmm, &mut tree);
+        let trimmed = tree.nodes.iter().map(|n| (n.name, n.interval.start, n.interval.stop)).collect::<Vec<_>>();
+        assert_eq!(trimmed, [
+            ("hexamer_region", 3, 17),
+            ("skip", 0, 3),
+            ("hex", 3, 9),
+            ("hexamer_region_skip", 9, 11),
+            ("hex", 11, 17),
+            ("skip", 17, 20),
+            ("skip2_skip", 20, 25),
+        ]);
+    }
+
+    #[test]
+    fn sampled_sva_strand() {
+        let settings = HMMBuildSettings::default();
+        let hmm = gen_sva_model(&settings);
+        let compiled = hmm.compile();
+        let best_score = |query: &[u8]| {
+            let (path, query_indexes) = compiled.query(query);
+            *compiled.path_scores(query, &path, &query_indexes).last().unwrap()
+        };
+        for seed in 0..10 {
+            let sequence = hmm.sample(seed).0.iter().map(|&b| b"ACGTN"[b as usize] as char).collect::<String>();
+            let reverse = reverse_complement(&sequence);
+            assert_eq!(reverse_complement(&reverse), sequence);
+            // An SVA should always score better on the strand it was sampled on
+            assert!(best_score(&sequence_to_bytes(&sequence)) > best_score(&sequence_to_bytes(&reverse)), "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn model_file_roundtrip() {
+        let settings = HMMBuildSettings::default().with_alignment_mode(AlignmentMode::Local);
+        let hmm = gen_sva_model(&settings);
+        let mut bytes = Vec::new();
+        hmm.write_model(&mut bytes).unwrap();
+        let read = HMM::read_model(bytes.as_slice()).unwrap();
+        assert_eq!(read, hmm);
+        let query = sequence_to_bytes(SVA_F_SEQ);
+        assert_eq!(read.query(&query), hmm.query(&query));
+
+        bytes[8] += 1;
+        assert!(matches!(HMM::read_model(bytes.as_slice()), Err(ModelFileError::Version(v)) if v == MODEL_FORMAT_VERSION + 1));
+        assert!(matches!(HMM::read_model(&b"ACGT"[..]), Err(ModelFileError::NotAModel)));
+    }
+
     #[test]
     fn complex_sva_test() {
         let settings = HMMBuildSettings::default();
@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+use std::ops::AddAssign;
+use rayon::prelude::*;
+use crate::builder::{HMMBuildError, HMMBuildSettings};
+use crate::hmm::{HMM, StateAnnotation, StateRole};
+
+/// Viterbi training: the tied build parameters are re-estimated from how often each of their
+/// transitions (and correct match emissions) is used along the viterbi paths of the training
+/// sequences, then the model is rebuilt and the sequences decoded again until nothing changes.
+/// Only works for models made entirely by the builder, since the transitions are classified by the
+/// state annotations the builder fills in (Dfam models have their own per-position parameters)
+/// * `build`: builds the model from a set of settings, e.g. `sva::gen_sva_model`
+/// * `queries`:
+/// * `initial`: starting settings
+/// * `max_iterations`:
+/// * `tolerance`: stops once no parameter changes by more than this
+pub fn viterbi_train<F>(
+    build: F,
+    queries: &[Vec<u8>],
+    initial: HMMBuildSettings,
+    max_iterations: usize,
+    tolerance: f64,
+) -> Result<HMMBuildSettings, HMMBuildError>
+where
+    F: Fn(&HMMBuildSettings) -> HMM,
+{
+    let mut settings = initial;
+    for iteration in 0..max_iterations {
+        let hmm = build(&settings);
+        let compiled = hmm.compile();
+        let (counts, score) = queries.par_iter()
+            .map(|query| {
+                let (path, query_indexes) = compiled.query(query);
+                let score = compiled.path_scores(query, &path, &query_indexes).last().copied().unwrap_or(0.0);
+                (ParameterCounts::from_path(&hmm, query, &path, &query_indexes), score)
+            })
+            .reduce(
+                || (ParameterCounts::default(), 0.0),
+                |(mut a, a_score), (b, b_score)| {
+                    a += b;
+                    (a, a_score + b_score)
+                }
+            );
+        let updated = counts.estimate(&settings)?;
+        eprintln!("Iteration {}: total viterbi log-likelihood {:.4}", iteration + 1, score);
+
+        let change = std::iter::zip(updated.named_values(), settings.named_values())
+            .map(|((_, a), (_, b))| (a - b).abs())
+            .fold(0.0, f64::max);
+        settings = updated;
+        if change <= tolerance {
+            break;
+        }
+    }
+    Ok(settings)
+}
+
+/// How often each outcome of the tied build parameters was used along viterbi paths
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ParameterCounts {
+    pub match_to_match: f64,
+    pub match_to_ins: f64,
+    pub match_to_del: f64,
+    /// Last match state to the end of its pHMM, which is match_to_match + match_to_del
+    pub match_to_end: f64,
+    pub ins_extend: f64,
+    pub ins_exit: f64,
+    pub del_extend: f64,
+    pub del_exit: f64,
+    pub loop_repeat: f64,
+    pub loop_skip: f64,
+    pub loop_exit: f64,
+    pub skip_to_skip: f64,
+    pub skip_exit: f64,
+    pub match_emit_correct: f64,
+    pub match_emit_incorrect: f64,
+}
+
+/// The kinds of states the tied parameters come out of, from the builder's annotations
+#[derive(Debug, PartialEq)]
+enum StateKind<'a> {
+    Match(&'a str, usize),
+    Insert(&'a str, usize),
+    Delete(&'a str, usize),
+    LoopEnd,
+    SkipState,
+    Other,
+}
+
+fn state_kind(annotation: &StateAnnotation) -> StateKind<'_> {
+    let region = annotation.region.as_str();
+    match (annotation.role, annotation.position) {
+        (StateRole::Match, Some(position)) => StateKind::Match(region, position),
+        (StateRole::Insert, Some(position)) => StateKind::Insert(region, position),
+        (StateRole::Delete, Some(position)) => StateKind::Delete(region, position),
+        (StateRole::LoopEnd, _) => StateKind::LoopEnd,
+        (StateRole::Skip, _) => StateKind::SkipState,
+        _ => StateKind::Other,
+    }
+}
+
+/// The ends of loop copies that can both leave the loop and go round again. With bounds on the
+/// copies, going round is forced before the minimum and leaving is forced at the maximum, so those
+/// transitions don't say anything about `loop_prob`
+fn optional_loop_ends(hmm: &HMM) -> HashSet<&str> {
+    let mut leaves = HashSet::new();
+    let mut repeats = HashSet::new();
+    for state in hmm.states.iter() {
+        for (prev, lp) in state.prev_states().iter().zip(state.prev_state_transitions()) {
+            if *lp == f64::NEG_INFINITY {
+                continue;
+            }
+            match state.annotation().role {
+                StateRole::End => leaves.insert(prev.as_str()),
+                _ => repeats.insert(prev.as_str()),
+            };
+        }
+    }
+    leaves.intersection(&repeats).copied().collect()
+}
+
+impl ParameterCounts {
+    /// Counts the transitions and match emissions along a path from `HMM::query`
+    /// * `hmm`: model the path came from
+    /// * `query`:
+    /// * `state_names`:
+    /// * `state_pos`:
+    pub fn from_path(hmm: &HMM, query: &[u8], state_names: &[&str], state_pos: &[usize]) -> Self {
+        let mut counts = ParameterCounts::default();
+        let index_map = hmm.get_index_map();
+        let optional_loop_ends = optional_loop_ends(hmm);
+        let annotations = state_names.iter()
+            .map(|s| hmm.states[index_map[*s]].annotation())
+            .collect::<Vec<_>>();
+
+        for step in 0..state_names.len() {
+            let kind = state_kind(annotations[step]);
+            if let StateKind::Match(_, _) = kind {
+                let base = query[state_pos[step] - 1];
+                let emission = hmm.states[index_map[state_names[step]]].emission().unwrap();
+                let consensus = (0..emission.len())
+                    .max_by(|a, b| emission[*a].total_cmp(&emission[*b]))
+                    .unwrap();
+                match base {
+                    4.. => {}, // N and the other ambiguity codes don't say which base it was
+                    b if b as usize == consensus => counts.match_emit_correct += 1.0,
+                    _ => counts.match_emit_incorrect += 1.0,
+                }
+            }
+
+            let Some(next) = annotations.get(step + 1) else {
+                break;
+            };
+            match (kind, state_kind(next)) {
+                (StateKind::Match(p, i), StateKind::Match(q, j)) if p == q && j == i + 1 => counts.match_to_match += 1.0,
+                (StateKind::Match(p, i), StateKind::Insert(q, j)) if p == q && j == i => counts.match_to_ins += 1.0,
+                (StateKind::Match(p, _), StateKind::Delete(q, _)) if p == q => counts.match_to_del += 1.0,
+                (StateKind::Match(p, _), _) if next.role == StateRole::End && next.region == p => counts.match_to_end += 1.0,
+                (StateKind::Insert(_, _), StateKind::Insert(_, _)) => counts.ins_extend += 1.0,
+                (StateKind::Insert(_, _), _) => counts.ins_exit += 1.0,
+                (StateKind::Delete(_, _), StateKind::Delete(_, _)) => counts.del_extend += 1.0,
+                (StateKind::Delete(_, _), StateKind::Match(_, _)) => counts.del_exit += 1.0,
+                (StateKind::LoopEnd, _) if !optional_loop_ends.contains(state_names[step]) => {},
+                (StateKind::LoopEnd, _) if next.role == StateRole::LoopStart => counts.loop_repeat += 1.0,
+                // The only region that can start straight after a copy is the loop's skip state
+                (StateKind::LoopEnd, _) if next.role == StateRole::Start => counts.loop_skip += 1.0,
+                (StateKind::LoopEnd, _) => counts.loop_exit += 1.0,
+                (StateKind::SkipState, StateKind::SkipState) => counts.skip_to_skip += 1.0,
+                (StateKind::SkipState, _) => counts.skip_exit += 1.0,
+                _ => {},
+            }
+        }
+        counts
+    }
+
+    /// Maximum likelihood settings for these counts, with one pseudocount per outcome so nothing
+    /// unseen in the training data becomes impossible. Parameters that were never used keep their
+    /// current value
+    /// * `current`: settings the counts came from
+    pub fn estimate(&self, current: &HMMBuildSettings) -> Result<HMMBuildSettings, HMMBuildError> {
+        let fit = |num: f64, other: f64, current: f64| {
+            if num + other == 0.0 { current } else { (num + 1.0) / (num + other + 2.0) }
+        };
+
+        let match_to_ins = fit(
+            self.match_to_ins,
+            self.match_to_match + self.match_to_del + self.match_to_end,
+            current.match_to_ins
+        );
+        // The last match state can't tell match from deletion, so only the others are used to
+        // split the rest
+        let current_match_share = current.match_to_match / (1.0 - current.match_to_ins);
+        let match_to_match = (1.0 - match_to_ins) * fit(self.match_to_match, self.match_to_del, current_match_share);
+
+        HMMBuildSettings::new(
+            match_to_match,
+            match_to_ins,
+            fit(self.ins_extend, self.ins_exit, current.ins_extend),
+            fit(self.del_extend, self.del_exit, current.del_extend),
+            fit(self.loop_repeat + self.loop_skip, self.loop_exit, current.loop_prob),
+            fit(self.loop_skip, self.loop_repeat, current.enter_skip_loop),
+            fit(self.skip_to_skip, self.skip_exit, current.skip_to_skip),
+            fit(self.match_emit_correct, self.match_emit_incorrect, current.match_emit_correct),
+        ).map(|s| HMMBuildSettings {
+            alignment_mode: current.alignment_mode,
+            hexamer_copies: current.hexamer_copies,
+            vntr_copies: current.vntr_copies,
+            ..s
+        })
+    }
+}
+
+impl AddAssign for ParameterCounts {
+    fn add_assign(&mut self, rhs: Self) {
+        self.match_to_match += rhs.match_to_match;
+        self.match_to_ins += rhs.match_to_ins;
+        self.match_to_del += rhs.match_to_del;
+        self.match_to_end += rhs.match_to_end;
+        self.ins_extend += rhs.ins_extend;
+        self.ins_exit += rhs.ins_exit;
+        self.del_extend += rhs.del_extend;
+        self.del_exit += rhs.del_exit;
+        self.loop_repeat += rhs.loop_repeat;
+        self.loop_skip += rhs.loop_skip;
+        self.loop_exit += rhs.loop_exit;
+        self.skip_to_skip += rhs.skip_to_skip;
+        self.skip_exit += rhs.skip_exit;
+        self.match_emit_correct += rhs.match_emit_correct;
+        self.match_emit_incorrect += rhs.match_emit_incorrect;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::*;
+    use crate::utils::sequence_to_bytes;
+
+    #[test]
+    fn count_path() {
+        let settings = HMMBuildSettings::default();
+        let hmm = create_pHMM(&sequence_to_bytes("ACGTAC"), &settings, Some("test"));
+        let path = ["test_start", "test_M0", "test_M1", "test_I1", "test_I1", "test_M2", "test_D3", "test_M4", "test_M5", "test_end"];
+        let pos = [0, 1, 2, 3, 4, 5, 5, 6, 7, 7];
+        let query = sequence_to_bytes("ACTTGAA");
+        let counts = ParameterCounts::from_path(&hmm, &query, &path, &pos);
+        assert_eq!(counts, ParameterCounts {
+            match_to_match: 2.0,
+            match_to_ins: 1.0,
+            match_to_del: 1.0,
+            match_to_end: 1.0,
+            ins_extend: 1.0,
+            ins_exit: 1.0,
+            del_exit: 1.0,
+            match_emit_correct: 4.0,
+            match_emit_incorrect: 1.0,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn count_bounded_loop() {
+        // Going from the first copy to the second and leaving after the third are forced, so only
+        // going on to the third counts
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let hmm = create_HMM_from_motifs_with_copies(&motifs, &["Rep1", "Rep2"], &settings, "test", CopyRange::new(2, Some(3)).unwrap());
+        let query = sequence_to_bytes(&[motifs[0], motifs[1], motifs[0]].concat());
+        let (path, pos) = hmm.query(&query);
+        let counts = ParameterCounts::from_path(&hmm, &query, &path, &pos);
+        assert_eq!((counts.loop_repeat, counts.loop_skip, counts.loop_exit), (1.0, 0.0, 0.0));
+
+        let unbounded = create_HMM_from_motifs(&motifs, &["Rep1", "Rep2"], &settings, "test");
+        let (path, pos) = unbounded.query(&query);
+        let counts = ParameterCounts::from_path(&unbounded, &query, &path, &pos);
+        assert_eq!((counts.loop_repeat, counts.loop_skip, counts.loop_exit), (2.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn training_fits_skip_state() {
+        // Only the skip parameter matters for a skip state, and every record goes through it once
+        let queries = ["ACGTACGTAC", "ACGTA", "ACGTACGTACGTACG"].map(sequence_to_bytes);
+        let fitted = viterbi_train(
+            |s| create_skip_state(s, Some("test")),
+            &queries,
+            HMMBuildSettings::default(),
+            5,
+            1e-9,
+        ).unwrap();
+        let (extend, exit) = (27.0, 3.0);
+        assert!((fitted.skip_to_skip - (extend + 1.0) / (extend + exit + 2.0)).abs() < 1e-12);
+    }
+}
@@ -4,25 +4,313 @@ use std::{
     path::Path,
     iter::zip
 };
+use thiserror::Error;
+use serde::Serialize;
+use crate::classify::ModelRank;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Interval {
     pub start: usize,
-    pub stop: usize
+    #[serde(rename = "end")]
+    pub stop: usize,
+    /// Mean posterior probability of the region over its span, only set by posterior decoding
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+    /// Log-odds score of the region against the null model in bits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bits: Option<f64>,
+    /// Alignment to the motif or consensus, for the regions of a single pHMM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alignment: Option<AlignmentSummary>,
 }
-fn char_to_index(c: char) -> u8 {
-    match c {
-        'A' => 0,
-        'C' => 1,
-        'G' => 2,
-        'T' => 3,
-        'N' => 4,
-        _ => panic!("Invalid character"),
+
+impl Interval {
+    /// Moves the interval to the other strand of a sequence of length `len`, e.g. from the reverse
+    /// complement that was typed back to the input. An unclosed interval (`usize::MAX` stop) stays
+    /// unclosed, with the start mapped to the position it started at on the typed strand
+    /// * `len`:
+    pub fn reverse_strand(&mut self, len: usize) {
+        (self.start, self.stop) = match self.stop {
+            usize::MAX => (len - self.start, usize::MAX),
+            stop => (len - stop, len - self.start),
+        };
+    }
+
+    /// Start and stop as the TSV output reports them, which is one less than the 0-based half-open
+    /// coordinates (a start of 0 stays 0, and so does an unclosed `usize::MAX` stop). BED, GFF3 and
+    /// JSON Lines use the coordinates themselves
+    pub fn tsv_bounds(&self) -> (usize, usize) {
+        let stop = match self.stop {
+            usize::MAX => usize::MAX,
+            stop => stop.saturating_sub(1),
+        };
+        (self.start.saturating_sub(1), stop)
+    }
+}
+
+/// Intervals of a path arranged by how their regions nest: a region like `hexamer_region` is the
+/// parent of the motif copies and skips inside it, and the element's top level regions have no
+/// parent. Nodes are in the order they start, so parents come before their children and a node's
+/// index is a stable ID
+#[derive(Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct IntervalTree<'a> {
+    pub nodes: Vec<IntervalNode<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IntervalNode<'a> {
+    #[serde(rename = "region")]
+    pub name: &'a str,
+    #[serde(flatten)]
+    pub interval: Interval,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+}
+
+impl<'a> IntervalTree<'a> {
+    /// * `intervals`: e.g. from `CompiledHmm::convert_to_intervals`
+    /// * `parents`: of each interval, from `CompiledHmm::interval_parents` for the same path
+    pub fn new(intervals: Vec<(&'a str, Interval)>, parents: &[Option<usize>]) -> Self {
+        let mut nodes = zip(intervals, parents)
+            .map(|((name, interval), parent)| IntervalNode { name, interval, parent: *parent, children: Vec::new() })
+            .collect::<Vec<_>>();
+        for id in 0..nodes.len() {
+            if let Some(parent) = nodes[id].parent {
+                nodes[parent].children.push(id);
+            }
+        }
+        IntervalTree { nodes }
+    }
+
+    /// The element's top level regions
+    pub fn roots(&self) -> impl Iterator<Item = &IntervalNode<'a>> {
+        self.nodes.iter().filter(|n| n.parent.is_none())
+    }
+
+    pub fn children(&self, id: usize) -> impl Iterator<Item = &IntervalNode<'a>> {
+        self.nodes[id].children.iter().map(|c| &self.nodes[*c])
+    }
+
+    /// Flat list of the intervals, as `convert_to_intervals` gives them
+    pub fn into_intervals(self) -> Vec<(&'a str, Interval)> {
+        self.nodes.into_iter().map(|n| (n.name, n.interval)).collect()
+    }
+}
+
+/// How a stretch of the query aligns to a motif or consensus
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AlignmentSummary {
+    /// Extended CIGAR: `=` match, `X` mismatch, `I` insertion and `D` deletion
+    pub cigar: String,
+    pub matches: usize,
+    /// Match states emitting a different base, or an N or other ambiguity code
+    pub mismatches: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+    /// Motif or consensus positions the alignment covers, from its first to its last match or
+    /// deletion (0-based, end exclusive)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<(usize, usize)>,
+}
+
+impl AlignmentSummary {
+    /// * `ops`: alignment operations in order, as CIGAR characters
+    pub fn from_ops(ops: impl IntoIterator<Item = char>) -> Self {
+        let mut summary = AlignmentSummary::default();
+        let mut run: Option<(char, usize)> = None;
+        for op in ops {
+            match op {
+                '=' => summary.matches += 1,
+                'X' => summary.mismatches += 1,
+                'I' => summary.insertions += 1,
+                'D' => summary.deletions += 1,
+                _ => panic!("Unknown alignment operation {op}"),
+            }
+            run = match run {
+                Some((c, n)) if c == op => Some((c, n + 1)),
+                Some((c, n)) => {
+                    summary.cigar.push_str(&format!("{n}{c}"));
+                    Some((op, 1))
+                }
+                None => Some((op, 1)),
+            };
+        }
+        if let Some((c, n)) = run {
+            summary.cigar.push_str(&format!("{n}{c}"));
+        }
+        summary
+    }
+
+    /// Percentage of the alignment columns that are matches
+    pub fn identity(&self) -> f64 {
+        let columns = self.matches + self.mismatches + self.insertions + self.deletions;
+        match columns {
+            0 => 0.0,
+            _ => 100.0 * self.matches as f64 / columns as f64,
+        }
     }
 }
+/// Query codes after A, C, G, T and N are the IUPAC ambiguity codes, in this order
+const AMBIGUITY_CODES: [char; 10] = ['R', 'Y', 'S', 'W', 'K', 'M', 'B', 'D', 'H', 'V'];
+
+/// Number of query codes (A, C, G, T, N and the other ambiguity codes)
+pub const N_CODES: usize = 5 + AMBIGUITY_CODES.len();
 
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum SequenceError {
+    #[error("Invalid character {character:?} at position {position}")]
+    InvalidCharacter {
+        character: char,
+        position: usize,
+    },
+    #[error("Sequence has {sequence} bases but {qualities} qualities")]
+    QualityLength {
+        sequence: usize,
+        qualities: usize,
+    },
+}
+
+fn char_to_index(c: char) -> Option<u8> {
+    match c.to_ascii_uppercase() {
+        'A' => Some(0),
+        'C' => Some(1),
+        'G' => Some(2),
+        'T' => Some(3),
+        'N' => Some(4),
+        c => AMBIGUITY_CODES.iter().position(|a| *a == c).map(|i| i as u8 + 5),
+    }
+}
+
+/// Indexes of the bases (A, C, G, T) a query code allows
+pub fn code_bases(code: u8) -> &'static [usize] {
+    match code {
+        0 => &[0],
+        1 => &[1],
+        2 => &[2],
+        3 => &[3],
+        4 => &[0, 1, 2, 3],
+        5 => &[0, 2],    // R
+        6 => &[1, 3],    // Y
+        7 => &[1, 2],    // S
+        8 => &[0, 3],    // W
+        9 => &[2, 3],    // K
+        10 => &[0, 1],   // M
+        11 => &[1, 2, 3], // B
+        12 => &[0, 2, 3], // D
+        13 => &[0, 1, 3], // H
+        14 => &[0, 1, 2], // V
+        _ => panic!("Invalid query code {code}"),
+    }
+}
+
+/// Adds probabilities in log space without leaving it, ignoring impossible (-inf) terms
+pub fn log_sum_exp(values: impl Iterator<Item = f64>) -> f64 {
+    values.fold(f64::NEG_INFINITY, |acc, x| {
+        if acc == f64::NEG_INFINITY {
+            return x;
+        }
+        if x == f64::NEG_INFINITY {
+            return acc;
+        }
+        let (hi, lo) = if acc > x { (acc, x) } else { (x, acc) };
+        hi + (lo - hi).exp().ln_1p()
+    })
+}
+
+/// Panics on invalid characters, use `try_sequence_to_bytes` for user input
 pub fn sequence_to_bytes(seq: &str) -> Vec<u8> {
-    seq.chars().map(char_to_index).collect()
+    try_sequence_to_bytes(seq).unwrap()
+}
+
+/// Encodes a sequence as query codes. Lowercase (soft-masked) bases and IUPAC ambiguity codes are
+/// accepted
+pub fn try_sequence_to_bytes(seq: &str) -> Result<Vec<u8>, SequenceError> {
+    seq.chars()
+        .enumerate()
+        .map(|(position, character)| {
+            char_to_index(character).ok_or(SequenceError::InvalidCharacter { character, position })
+        })
+        .collect()
+}
+
+/// A query the decoders can run on: its query codes, and optionally the probability that each base
+/// is a sequencing error
+pub trait QuerySeq {
+    fn codes(&self) -> &[u8];
+
+    /// Emissions of a base are mixed with a uniform one by this much
+    #[inline]
+    fn error_prob(&self, _i: usize) -> Option<f64> {
+        None
+    }
+}
+
+impl QuerySeq for [u8] {
+    fn codes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl QuerySeq for Vec<u8> {
+    fn codes(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Query codes along with the error probabilities of their base calls, e.g. from a FASTQ record
+#[derive(Clone, Debug)]
+pub struct QualityQuery {
+    codes: Vec<u8>,
+    error_probs: Vec<f64>,
+}
+
+impl QualityQuery {
+    /// * `codes`:
+    /// * `qualities`: Phred+33 encoded qualities, as in FASTQ
+    pub fn from_phred(codes: Vec<u8>, qualities: &[u8]) -> Result<Self, SequenceError> {
+        if codes.len() != qualities.len() {
+            return Err(SequenceError::QualityLength { sequence: codes.len(), qualities: qualities.len() });
+        }
+        let error_probs = qualities.iter()
+            .map(|q| 10f64.powf(-(q.saturating_sub(33) as f64) / 10.0))
+            .collect();
+        Ok(QualityQuery { codes, error_probs })
+    }
+}
+
+impl QuerySeq for QualityQuery {
+    fn codes(&self) -> &[u8] {
+        &self.codes
+    }
+
+    #[inline]
+    fn error_prob(&self, i: usize) -> Option<f64> {
+        Some(self.error_probs[i])
+    }
+}
+
+/// Ambiguity codes are complemented too, other characters (e.g. N, S, W) are kept as they are.
+/// Lowercase bases stay lowercase
+pub fn reverse_complement(seq: &str) -> String {
+    seq.chars().rev().map(|c| {
+        let complement = match c.to_ascii_uppercase() {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            'R' => 'Y',
+            'Y' => 'R',
+            'K' => 'M',
+            'M' => 'K',
+            'B' => 'V',
+            'V' => 'B',
+            'D' => 'H',
+            'H' => 'D',
+            _ => return c,
+        };
+        if c.is_ascii_lowercase() { complement.to_ascii_lowercase() } else { complement }
+    }).collect()
 }
 
 pub fn pprint_intervals<T: Write>(writer: &mut T, intervals: Vec<(&str, Interval)>) -> io::Result<()> {
@@ -32,26 +320,141 @@ pub fn pprint_intervals<T: Write>(writer: &mut T, intervals: Vec<(&str, Interval
     Ok(())
 }
 
-pub fn write_header(writer: &mut impl Write, write_hmm_state: bool, write_query_seq: bool) -> io::Result<()> {
-    match write_hmm_state {
-        true => writeln!(writer, "ID\tstate\tquery_i\tquery_base"),
-        false => match write_query_seq {
-            true => writeln!(writer, "ID\tregion\tstart\tend\tseq"),
-            false => writeln!(writer, "ID\tregion\tstart\tend")
+/// Optional columns of the interval output
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OutputColumns {
+    pub query_seq: bool,
+    pub confidence: bool,
+    pub bits: bool,
+    pub log_likelihood: bool,
+    pub path_rank: bool,
+    pub strand: bool,
+    pub alignment: bool,
+    pub parent_ids: bool,
+}
+
+/// Per-record values that are repeated on every interval line
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecordScores {
+    pub log_likelihood: Option<f64>,
+    pub viterbi_bits: Option<f64>,
+    pub forward_bits: Option<f64>,
+    /// Rank of the path the intervals came from when more than the best path is output (0 = best)
+    pub path_rank: Option<usize>,
+    /// Log probability of the best path minus this one
+    pub score_gap: Option<f64>,
+    /// Strand of the input that was typed, when both strands are tried
+    pub strand: Option<char>,
+}
+
+pub fn write_header(writer: &mut impl Write, write_hmm_state: bool, columns: &OutputColumns) -> io::Result<()> {
+    let mut header = match write_hmm_state {
+        true => "ID\tstate\tquery_i\tquery_base".to_string(),
+        false => match columns.query_seq {
+            true => "ID\tregion\tstart\tend\tseq".to_string(),
+            false => "ID\tregion\tstart\tend".to_string()
+        }
+    };
+    if !write_hmm_state {
+        if columns.parent_ids {
+            header.push_str("\tinterval_id\tparent_id");
+        }
+        if columns.confidence {
+            header.push_str("\tconfidence");
+        }
+        if columns.bits {
+            header.push_str("\tbits");
+        }
+        if columns.alignment {
+            header.push_str("\tcigar\tidentity\tmismatches\tinsertions\tdeletions");
+        }
+        if columns.log_likelihood {
+            header.push_str("\tlog_likelihood");
+        }
+        if columns.bits {
+            header.push_str("\tviterbi_bits\tforward_bits");
+        }
+        if columns.path_rank {
+            header.push_str("\tpath_rank\tscore_gap");
+        }
+        if columns.strand {
+            header.push_str("\tstrand");
         }
     }
+    writeln!(writer, "{header}")
 }
 
-pub fn tsvprint_intervals(writer: &mut impl Write, seqname: &str, intervals: Vec<(&str, Interval)>) -> io::Result<()> {
-    for (s, interval) in intervals {
-        writeln!(writer, "{seqname}\t{s}\t{}\t{}", interval.start, interval.stop)?
+/// Adds a column if it's in the output, with NA when there's no value, so every row has the
+/// columns of the header
+fn push_column(extra: &mut String, enabled: bool, value: Option<f64>) {
+    match (enabled, value) {
+        (false, _) => {}
+        (true, Some(v)) => extra.push_str(&format!("\t{v:.4}")),
+        (true, None) => extra.push_str("\tNA"),
+    }
+}
+
+/// Formats the optional interval columns followed by the per-record columns
+fn extra_columns(tree: &IntervalTree, id: usize, scores: &RecordScores, columns: &OutputColumns) -> String {
+    let mut extra = String::new();
+    let interval = &tree.nodes[id].interval;
+    if columns.parent_ids {
+        let parent = tree.nodes[id].parent.map_or("NA".to_string(), |p| p.to_string());
+        extra.push_str(&format!("\t{id}\t{parent}"));
+    }
+    push_column(&mut extra, columns.confidence, interval.confidence);
+    push_column(&mut extra, columns.bits, interval.bits);
+    if columns.alignment {
+        // Regions that aren't a single pHMM have no alignment
+        match &interval.alignment {
+            Some(a) => extra.push_str(&format!(
+                "\t{}\t{:.2}\t{}\t{}\t{}", a.cigar, a.identity(), a.mismatches, a.insertions, a.deletions
+            )),
+            None => extra.push_str("\tNA\tNA\tNA\tNA\tNA"),
+        }
+    }
+    push_column(&mut extra, columns.log_likelihood, scores.log_likelihood);
+    push_column(&mut extra, columns.bits, scores.viterbi_bits);
+    push_column(&mut extra, columns.bits, scores.forward_bits);
+    if columns.path_rank {
+        let rank = scores.path_rank.map_or("NA".to_string(), |r| r.to_string());
+        extra.push_str(&format!("\t{rank}"));
+    }
+    push_column(&mut extra, columns.path_rank, scores.score_gap);
+    if columns.strand {
+        extra.push_str(&format!("\t{}", scores.strand.map_or("NA".to_string(), |s| s.to_string())));
+    }
+    extra
+}
+
+pub fn tsvprint_intervals(writer: &mut impl Write, seqname: &str, intervals: &IntervalTree, scores: &RecordScores, columns: &OutputColumns) -> io::Result<()> {
+    for (id, node) in intervals.nodes.iter().enumerate() {
+        let extra = extra_columns(intervals, id, scores, columns);
+        let (start, stop) = node.interval.tsv_bounds();
+        writeln!(writer, "{seqname}\t{}\t{start}\t{stop}{extra}", node.name)?
     }
     Ok(())
 }
 
-pub fn tsvprint_intervals_withseq(writer: &mut impl Write, seqname: &str, query: &str, intervals: Vec<(&str, Interval)>) -> io::Result<()> {
-    for (s, interval) in intervals {
-        writeln!(writer, "{seqname}\t{s}\t{}\t{}\t{}", interval.start, interval.stop, &query[interval.start..interval.stop])?
+/// Header of the classify output. `score` names the score column
+pub fn write_classify_header(writer: &mut impl Write, score: &str) -> io::Result<()> {
+    writeln!(writer, "ID\tmodel\trank\t{score}\tmargin")
+}
+
+/// One line per model, best first, with the rank starting at 1
+pub fn tsvprint_model_ranks(writer: &mut impl Write, seqname: &str, ranks: &[ModelRank]) -> io::Result<()> {
+    for (rank, model) in ranks.iter().enumerate() {
+        let margin = model.margin.map_or("NA".to_string(), |m| format!("{m:.4}"));
+        writeln!(writer, "{seqname}\t{}\t{}\t{:.4}\t{margin}", model.name, rank + 1, model.score)?;
+    }
+    Ok(())
+}
+
+pub fn tsvprint_intervals_withseq(writer: &mut impl Write, seqname: &str, query: &str, intervals: &IntervalTree, scores: &RecordScores, columns: &OutputColumns) -> io::Result<()> {
+    for (id, node) in intervals.nodes.iter().enumerate() {
+        let extra = extra_columns(intervals, id, scores, columns);
+        let (start, stop) = node.interval.tsv_bounds();
+        writeln!(writer, "{seqname}\t{}\t{start}\t{stop}\t{}{extra}", node.name, &query[start..stop])?
     }
     Ok(())
 }
@@ -80,3 +483,82 @@ pub fn open_write(f: Option<&Path>) -> io::Result<Box<dyn Write>> {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{HMMBuildSettings, append_HMM, create_HMM_from_motifs, create_pHMM};
+
+    #[test]
+    fn missing_values_keep_columns() {
+        let columns = OutputColumns {
+            confidence: true,
+            bits: true,
+            log_likelihood: true,
+            path_rank: true,
+            strand: true,
+            ..Default::default()
+        };
+        let interval = |confidence| Interval { start: 0, stop: 6, confidence, bits: None, alignment: None };
+        let tree = IntervalTree::new(vec![("region", interval(Some(0.5))), ("hex", interval(None))], &[None, Some(0)]);
+        let scores = RecordScores { viterbi_bits: Some(2.0), ..Default::default() };
+
+        let mut output = Vec::new();
+        write_header(&mut output, false, &columns).unwrap();
+        tsvprint_intervals(&mut output, "read1", &tree, &scores, &columns).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let rows = output.lines().map(|l| l.split('\t').collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert!(rows.iter().all(|r| r.len() == rows[0].len()), "{output}");
+        assert_eq!(rows[1][4..], ["0.5000", "NA", "NA", "2.0000", "NA", "NA", "NA", "NA"]);
+        assert_eq!(rows[2][4], "NA");
+    }
+
+    #[test]
+    fn tsv_coordinates() {
+        let interval = |start, stop| Interval { start, stop, confidence: None, bits: None, alignment: None };
+        let intervals = vec![
+            ("region", interval(0, 26)),
+            ("Rep1", interval(0, 9)),
+            ("Rep2", interval(9, 17)),
+            ("Rep1", interval(17, usize::MAX)),
+        ];
+        let tree = IntervalTree::new(intervals, &[None, Some(0), Some(0), Some(0)]);
+        let mut output = Vec::new();
+        tsvprint_intervals(&mut output, "read1", &tree, &RecordScores::default(), &OutputColumns::default()).unwrap();
+        let spans = String::from_utf8(output).unwrap()
+            .lines()
+            .map(|l| l.split('\t').skip(2).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, ["0 25", "0 8", "8 16", format!("16 {}", usize::MAX).as_str()]);
+    }
+
+    #[test]
+    fn mixed_case_reverse_complement() {
+        assert_eq!(reverse_complement("ACgtRyNn"), "nNrYacGT");
+        assert_eq!(reverse_complement(&reverse_complement("aCGtmKbvdH")), "aCGtmKbvdH");
+    }
+
+    #[test]
+    fn interval_tree() {
+        let settings = HMMBuildSettings::default();
+        let motifs = ["ACGTGCGAT", "GTAACGAG"];
+        let hmm = append_HMM(vec![
+            create_pHMM(&sequence_to_bytes("TTGACCAT"), &settings, Some("left")),
+            create_HMM_from_motifs(&motifs, &["a", "b"], &settings, "rep"),
+        ]);
+        let query = sequence_to_bytes(&["TTGACCAT", motifs[0], motifs[1]].concat());
+        let (path, query_indexes) = hmm.query(&query);
+        let tree = hmm.interval_tree(path.clone(), query_indexes.clone());
+        let nodes = tree.nodes.iter().map(|n| (n.name, n.parent)).collect::<Vec<_>>();
+        assert_eq!(nodes, [("left", None), ("rep", None), ("a", Some(1)), ("b", Some(1))]);
+        assert_eq!(tree.roots().map(|n| n.name).collect::<Vec<_>>(), ["left", "rep"]);
+        assert_eq!(tree.children(1).map(|n| n.name).collect::<Vec<_>>(), ["a", "b"]);
+
+        let compiled = crate::compiled::CompiledHmm::new(&hmm);
+        assert_eq!(compiled.interval_parents(&path), [None, None, Some(1), Some(1)]);
+        fn bounds(intervals: Vec<(&str, Interval)>) -> Vec<(&str, usize, usize)> {
+            intervals.into_iter().map(|(n, i)| (n, i.start, i.stop)).collect()
+        }
+        assert_eq!(bounds(tree.into_intervals()), bounds(hmm.convert_to_intervals(path, query_indexes)));
+    }
+}